@@ -12,9 +12,9 @@ import { WebRTC } from '@multiformats/multiaddr-matcher'
 import { byteStream } from 'it-byte-stream'
 import { createLibp2p } from 'libp2p'
 import { fromString, toString } from 'uint8arrays'
-import { decodeAddress, encodeAddress } from '@polkadot/keyring'
-import { cryptoWaitReady, sr25519Sign, sr25519Verify, sr25519PairFromSeed } from '@polkadot/util-crypto'
-import { hexToU8a } from '@polkadot/util'
+import { DEV_PHRASE, decodeAddress, encodeAddress } from '@polkadot/keyring'
+import { cryptoWaitReady, keyExtractSuri, keyFromPath, mnemonicToMiniSecret, mnemonicValidate, sr25519Sign, sr25519Verify, sr25519PairFromSeed } from '@polkadot/util-crypto'
+import { hexToU8a, isHex, stringToU8a, u8aEq, u8aWrapBytes } from '@polkadot/util'
 import { createEd25519PeerId } from '@libp2p/peer-id-factory'
 
 // Constants
@@ -28,6 +28,25 @@ const CONNECTION_PERMISSION_PROTOCOL = '/libp2p/examples/connection-permission/1
 const CONNECTION_TIMEOUT = 10000
 const STREAM_TIMEOUT = 5000
 const CHAT_STREAM_TIMEOUT = 5000
+const PERMISSION_REQUEST_TIMEOUT = 5 * 60 * 1000 // Used when the relay does not report an expiry
+
+// Heartbeat Constants
+const HEARTBEAT_INTERVAL = 5000
+const HEARTBEAT_TIMEOUT = 3000
+const SLOW_PEER_RTT = 1000 // Round trips above this many milliseconds mark a peer as slow
+const OFFLINE_AFTER_MISSED_HEARTBEATS = 3
+
+// Message Envelope Constants
+const SUPPORTED_ENVELOPE_VERSIONS = [1]
+const MESSAGE_KIND_CHAT = 'chat'
+const MESSAGE_KIND_ACK = 'ack'
+
+// Delivery Constants
+const FRAME_DELIMITER = '\n' // JSON.stringify never emits a raw newline, so it can delimit envelopes
+const MAX_FRAME_SIZE = 1024 * 1024
+const RESEND_BASE_DELAY = 1000 // Doubled after every unacknowledged attempt
+const MAX_SEND_ATTEMPTS = 5
+const RECEIVE_WINDOW = 1024 // Sequence numbers tracked above the low-water mark of each peer
 
 // DOM Elements
 const output = document.getElementById('output')
@@ -37,28 +56,48 @@ const sendSection = document.getElementById('send-section')
 class SessionState {
   constructor() {
     this.peerMultiaddr = null
+    this.peerId = null
     this.chatStream = null
     this.mySS58Address = null
     this.mySecretKey = null
+    this.myKeyPair = null // Key pair derived from mySecretKey, used to sign envelopes
+    this.verifiedPeers = new Map() // Peer ID -> SS58 address proven during connection proof of possession
     this.connectionChallenges = new Map() // Store pending connection challenges
     this.pendingPermissionRequests = new Map() // Store incoming permission requests
     this.outgoingPermissionRequests = new Map() // Store outgoing permission requests
+    this.permissionRequestKeys = new Map() // Target SS58 address -> idempotency key of the attempt in progress
+    this.envelopeVersions = new Map() // Peer ID -> message envelope version negotiated with that peer
+    this.sendSequences = new Map() // Peer ID -> sequence number of the last chat envelope we sent to that peer
+    this.receiveWindows = new Map() // Peer ID -> { lowWaterMark, received } of accepted envelope sequence numbers
+    this.peerPresence = new Map() // Peer ID -> { ss58Address, lastSeen, rtt, missedHeartbeats }
+    this.unacknowledgedEnvelopes = new Map() // Peer ID and sequence number -> { data, attempts, timer }
   }
 
   reset() {
     this.peerMultiaddr = null
+    this.peerId = null
     this.chatStream = null
     this.mySS58Address = null
     this.mySecretKey = null
+    this.myKeyPair = null
+    this.verifiedPeers.clear()
     this.connectionChallenges.clear()
     this.pendingPermissionRequests.clear()
     this.outgoingPermissionRequests.clear()
+    this.permissionRequestKeys.clear()
+    this.envelopeVersions.clear()
+    this.sendSequences.clear()
+    this.receiveWindows.clear()
+    this.peerPresence.clear()
+    this.unacknowledgedEnvelopes.forEach(({ timer }) => clearTimeout(timer))
+    this.unacknowledgedEnvelopes.clear()
   }
 }
 
 // Global State
 let sessionState = new SessionState()
 let cryptoReady = false
+let lostChatWrites = 0 // Chat writes to lose in transit, set through the loseChatWrites test hook
 
 // Utility Functions
 const appendOutput = (message) => {
@@ -99,8 +138,32 @@ const initializeCrypto = async () => {
   }
 }
 
-// Sign a message with the secret key
-const signMessage = async (message, secretKey) => {
+// Derive an sr25519 key pair from a secret, following subkey/polkadot-js semantics.
+// Accepts a 0x-prefixed 32-byte seed or a secret URI such as `mnemonic//hard/soft///password`,
+// `0xseed//hard` or a development account like `//Alice`
+const keyPairFromSecret = async (secret) => {
+  await initializeCrypto()
+
+  const suri = secret.startsWith('//') ? `${DEV_PHRASE}${secret}` : secret
+  const { password, path, phrase } = keyExtractSuri(suri)
+
+  let seed
+  if (isHex(phrase, 256)) {
+    seed = hexToU8a(phrase)
+  } else if (phrase.startsWith('0x')) {
+    throw new Error('Secret key must be 32 bytes (64 hex characters) with 0x prefix')
+  } else {
+    if (!mnemonicValidate(phrase)) {
+      throw new Error('Secret must be a 32-byte hex seed or a valid mnemonic phrase')
+    }
+    seed = mnemonicToMiniSecret(phrase, password)
+  }
+
+  return keyFromPath(sr25519PairFromSeed(seed), path, 'sr25519')
+}
+
+// Sign a message with a key pair derived once by keyPairFromSecret
+const signMessage = async (message, pair) => {
   await initializeCrypto()
 
   // Convert message to Uint8Array
@@ -114,16 +177,9 @@ const signMessage = async (message, secretKey) => {
     messageBytes = new TextEncoder().encode(message)
   }
 
-  // Convert secret key to Uint8Array
-  const secretKeyBytes = typeof secretKey === 'string'
-    ? hexToU8a(secretKey.startsWith('0x') ? secretKey : '0x' + secretKey)
-    : new Uint8Array(secretKey)
-
-  // Create key pair from secret key
-  const pair = sr25519PairFromSeed(secretKeyBytes)
-
-  // Sign the message
-  const signature = sr25519Sign(messageBytes, pair)
+  // Sign the <Bytes>-wrapped message, like polkadot-js signRaw, so the signature
+  // also verifies in existing Substrate tooling
+  const signature = sr25519Sign(u8aWrapBytes(messageBytes), pair)
 
   return signature
 }
@@ -134,11 +190,148 @@ const signatureToHex = (signature) => {
 }
 
 // Generate a random challenge for connection proof of possession
+// Connection signatures cover the challenge and the signer's libp2p Peer ID, which the transport
+// authenticates, so a peer cannot pass off a signature it obtained from another connection
+const connectionSigningMessage = (challenge, signerPeerId) => `${challenge}:${signerPeerId}`
+
 const generateConnectionChallenge = () => {
   const challenge = crypto.getRandomValues(new Uint8Array(32))
   return Array.from(challenge).map(b => b.toString(16).padStart(2, '0')).join('')
 }
 
+// Message Envelope Functions
+const negotiateEnvelopeVersion = (remoteVersions) => {
+  if (!Array.isArray(remoteVersions) || remoteVersions.length === 0) {
+    throw new Error(`Peer did not advertise message envelope versions; it may be running an older client (supported: ${SUPPORTED_ENVELOPE_VERSIONS.join(', ')})`)
+  }
+
+  const commonVersions = SUPPORTED_ENVELOPE_VERSIONS.filter(version => remoteVersions.includes(version))
+  if (commonVersions.length === 0) {
+    throw new Error(`No compatible message envelope version. Ours: ${SUPPORTED_ENVELOPE_VERSIONS.join(', ')}, peer: ${remoteVersions.join(', ')}`)
+  }
+
+  return Math.max(...commonVersions)
+}
+
+// Bytes covered by the sender's signature, <Bytes>-wrapped like every other signature we produce
+const envelopeSigningBytes = ({ version, kind, sessionId, sequence, sender, payload }) =>
+  u8aWrapBytes(stringToU8a(JSON.stringify([version, kind, sessionId, sequence, sender, payload])))
+
+const nextSendSequence = (remotePeerId, sessionState) => {
+  const sequence = (sessionState.sendSequences.get(remotePeerId) ?? 0) + 1
+  sessionState.sendSequences.set(remotePeerId, sequence)
+  return sequence
+}
+
+const signEnvelope = (envelope, keyPair) => ({
+  ...envelope,
+  signature: signatureToHex(sr25519Sign(envelopeSigningBytes(envelope), keyPair))
+})
+
+const serializeEnvelope = (envelope) => fromString(JSON.stringify(envelope) + FRAME_DELIMITER)
+
+// Chat envelopes take the next sequence number for the peer. Acknowledgements carry the sequence
+// number of the envelope they acknowledge instead, so they never leave gaps in the peer's window
+const encodeEnvelope = (kind, payload, remotePeerId, sessionState, sequence) => {
+  if (!sessionState.mySS58Address || !sessionState.myKeyPair) {
+    throw new Error('Register an SS58 address before sending messages')
+  }
+
+  const version = sessionState.envelopeVersions.get(remotePeerId)
+  if (version === undefined) {
+    throw new Error('No message envelope version agreed with this peer')
+  }
+
+  const envelope = {
+    version,
+    kind,
+    sessionId,
+    sequence: sequence ?? nextSendSequence(remotePeerId, sessionState),
+    sender: sessionState.mySS58Address,
+    payload
+  }
+  return { sequence: envelope.sequence, data: serializeEnvelope(signEnvelope(envelope, sessionState.myKeyPair)) }
+}
+
+// Decode an envelope and check it was signed by the SS58 address the peer proved it controls
+const decodeEnvelope = (frame, expectedSender) => {
+  let envelope
+  try {
+    envelope = JSON.parse(frame)
+  } catch (error) {
+    throw new Error('Message is not an envelope (peer may be running an older client)')
+  }
+
+  if (envelope === null || typeof envelope !== 'object' || envelope.version === undefined) {
+    throw new Error('Message is not an envelope (peer may be running an older client)')
+  }
+
+  if (!SUPPORTED_ENVELOPE_VERSIONS.includes(envelope.version)) {
+    throw new Error(`Unsupported envelope version ${envelope.version} (supported: ${SUPPORTED_ENVELOPE_VERSIONS.join(', ')})`)
+  }
+
+  if (typeof envelope.kind !== 'string' || envelope.payload === undefined) {
+    throw new Error('Malformed envelope: kind and payload are required')
+  }
+
+  if (envelope.sender !== expectedSender) {
+    throw new Error(`Envelope sender ${envelope.sender} does not match verified peer ${expectedSender}`)
+  }
+
+  if (typeof envelope.signature !== 'string' ||
+    !sr25519Verify(envelopeSigningBytes(envelope), hexToU8a(envelope.signature), decodeAddress(envelope.sender))) {
+    throw new Error('Invalid envelope signature')
+  }
+
+  return envelope
+}
+
+// Reject envelopes from another session of the peer. The sender's session ID is its libp2p peer ID,
+// which is fresh for every page load
+const checkEnvelopeSession = (envelope, remotePeerId) => {
+  if (envelope.sessionId !== remotePeerId) {
+    throw new Error(`Envelope belongs to session ${envelope.sessionId}, not the connected peer's session`)
+  }
+
+  if (!Number.isInteger(envelope.sequence) || envelope.sequence < 1) {
+    throw new Error(`Invalid envelope sequence ${envelope.sequence}`)
+  }
+}
+
+// Report whether a chat envelope is new. Every sequence number at or below the low-water mark has
+// been seen, and `received` holds the ones seen above it, so a resend that fills a gap is still
+// delivered while a duplicate is not. The caller acknowledges duplicates too, because they are
+// usually resends whose acknowledgement was lost
+const checkEnvelopeReplay = (envelope, remotePeerId, sessionState) => {
+  checkEnvelopeSession(envelope, remotePeerId)
+
+  let receiveWindow = sessionState.receiveWindows.get(remotePeerId)
+  if (!receiveWindow) {
+    receiveWindow = { lowWaterMark: 0, received: new Set() }
+    sessionState.receiveWindows.set(remotePeerId, receiveWindow)
+  }
+
+  const { sequence } = envelope
+  if (sequence <= receiveWindow.lowWaterMark || receiveWindow.received.has(sequence)) {
+    return false
+  }
+  receiveWindow.received.add(sequence)
+
+  // Gaps older than the receiveWindow belong to envelopes the sender has long given up on
+  if (sequence - receiveWindow.lowWaterMark > RECEIVE_WINDOW) {
+    receiveWindow.lowWaterMark = sequence - RECEIVE_WINDOW
+    receiveWindow.received.forEach((seen) => {
+      if (seen <= receiveWindow.lowWaterMark) receiveWindow.received.delete(seen)
+    })
+  }
+
+  while (receiveWindow.received.has(receiveWindow.lowWaterMark + 1)) {
+    receiveWindow.lowWaterMark++
+    receiveWindow.received.delete(receiveWindow.lowWaterMark)
+  }
+  return true
+}
+
 // Handle connection challenge requests
 const handleConnectionChallengeRequest = async (request, connection, sessionState) => {
   if (!sessionState.mySS58Address || !sessionState.mySecretKey) {
@@ -146,9 +339,12 @@ const handleConnectionChallengeRequest = async (request, connection, sessionStat
   }
 
   if (request.action === 'initiate') {
-    // Peer A wants to connect - generate challenge for them
-    const challenge = generateConnectionChallenge()
+    // Peer A wants to connect - agree on an envelope version and generate challenge for them
     const peerId = connection.remotePeer.toString()
+    const envelopeVersion = negotiateEnvelopeVersion(request.envelopeVersions)
+    sessionState.envelopeVersions.set(peerId, envelopeVersion)
+
+    const challenge = generateConnectionChallenge()
 
     // Store challenge with expiration (5 minutes)
     const expiresAt = Date.now() + (5 * 60 * 1000)
@@ -159,7 +355,7 @@ const handleConnectionChallengeRequest = async (request, connection, sessionStat
     })
 
     appendOutput(`Generated connection challenge for peer: ${peerId}`)
-    return { success: true, challenge }
+    return { success: true, challenge, envelopeVersion }
 
   } else if (request.action === 'respond') {
     // Peer A is responding with their signature
@@ -178,7 +374,7 @@ const handleConnectionChallengeRequest = async (request, connection, sessionStat
     }
 
     // Verify the signature
-    const isValid = await verifyConnectionSignature(request.ss58Address, challenge, signature)
+    const isValid = await verifyConnectionSignature(request.ss58Address, connectionSigningMessage(challenge, peerId), signature)
     if (!isValid) {
       return { success: false, error: 'Invalid signature' }
     }
@@ -191,7 +387,7 @@ const handleConnectionChallengeRequest = async (request, connection, sessionStat
     return { success: true, message: 'Challenge verified' }
 
   } else if (request.action === 'challenge') {
-    // Peer B wants to challenge Peer A back
+    // Peer A challenges us to prove our address, and we challenge Peer A back
     const peerId = connection.remotePeer.toString()
     const storedChallenge = sessionState.connectionChallenges.get(peerId)
 
@@ -199,13 +395,25 @@ const handleConnectionChallengeRequest = async (request, connection, sessionStat
       return { success: false, error: 'No verified connection found' }
     }
 
+    if (typeof request.challenge !== 'string' || !isHex(`0x${request.challenge}`, 256)) {
+      return { success: false, error: 'A 32-byte hex challenge is required' }
+    }
+
+    // Prove we control our address on this connection
+    const signature = await signMessage(connectionSigningMessage(request.challenge, node.peerId.toString()), sessionState.myKeyPair)
+
     // Generate our challenge for them
     const ourChallenge = generateConnectionChallenge()
     storedChallenge.ourChallenge = ourChallenge
     storedChallenge.ourChallengeExpires = Date.now() + (5 * 60 * 1000)
 
     appendOutput(`Generated mutual challenge for peer: ${peerId}`)
-    return { success: true, challenge: ourChallenge }
+    return {
+      success: true,
+      challenge: ourChallenge,
+      ss58Address: sessionState.mySS58Address,
+      signature: signatureToHex(signature)
+    }
 
   } else if (request.action === 'verify') {
     // Peer A is responding to our challenge
@@ -227,13 +435,14 @@ const handleConnectionChallengeRequest = async (request, connection, sessionStat
     }
 
     // Verify the signature
-    const isValid = await verifyConnectionSignature(request.ss58Address, challenge, signature)
+    const isValid = await verifyConnectionSignature(request.ss58Address, connectionSigningMessage(challenge, peerId), signature)
     if (!isValid) {
       return { success: false, error: 'Invalid signature' }
     }
 
     // Both challenges verified - connection established
     storedChallenge.status = 'established'
+    sessionState.verifiedPeers.set(peerId, request.ss58Address)
     sessionState.connectionChallenges.delete(peerId) // Clean up
 
     appendOutput(`Mutual connection challenge verified - connection established!`)
@@ -245,12 +454,12 @@ const handleConnectionChallengeRequest = async (request, connection, sessionStat
 }
 
 // Verify connection signature
-const verifyConnectionSignature = async (ss58Address, challenge, signature) => {
+const verifyConnectionSignature = async (ss58Address, message, signature) => {
   try {
     await initializeCrypto()
 
-    // Convert challenge to bytes
-    const challengeBytes = hexToU8a('0x' + challenge)
+    // Convert message to bytes, the same way signMessage encodes text
+    const challengeBytes = stringToU8a(message)
 
     // Convert signature to Uint8Array
     const signatureBytes = typeof signature === 'string'
@@ -260,8 +469,10 @@ const verifyConnectionSignature = async (ss58Address, challenge, signature) => {
     // Decode the SS58 address to get the public key
     const publicKey = decodeAddress(ss58Address)
 
-    // Verify the signature
-    const isValid = sr25519Verify(challengeBytes, signatureBytes, publicKey)
+    // Verify the signature over the <Bytes>-wrapped challenge, falling back to the
+    // bare challenge for peers running older clients
+    const isValid = sr25519Verify(u8aWrapBytes(challengeBytes), signatureBytes, publicKey) ||
+      sr25519Verify(challengeBytes, signatureBytes, publicKey)
 
     return isValid
   } catch (error) {
@@ -271,7 +482,16 @@ const verifyConnectionSignature = async (ss58Address, challenge, signature) => {
 }
 
 // Permission Request Functions
-const requestConnectionPermission = async (targetSS58Address, node, sessionState) => {
+// Repeated submissions for the same target reuse one idempotency key until the attempt finishes,
+// so the relay returns the existing request instead of creating another one
+const permissionRequestKey = (targetSS58Address, sessionState) => {
+  if (!sessionState.permissionRequestKeys.has(targetSS58Address)) {
+    sessionState.permissionRequestKeys.set(targetSS58Address, `${sessionId}:${targetSS58Address}:${Date.now()}`)
+  }
+  return sessionState.permissionRequestKeys.get(targetSS58Address)
+}
+
+const requestConnectionPermission = async (targetSS58Address, node, sessionState, idempotencyKey = permissionRequestKey(targetSS58Address, sessionState)) => {
   const relayConnection = getRelayConnection(node)
   if (!relayConnection) {
     throw new Error('No relay connection found')
@@ -294,7 +514,8 @@ const requestConnectionPermission = async (targetSS58Address, node, sessionState
       action: 'request',
       targetSS58Address,
       requesterSS58Address: sessionState.mySS58Address,
-      requesterPeerId: node.peerId.toString()
+      requesterPeerId: node.peerId.toString(),
+      idempotencyKey
     }
     const message = JSON.stringify(request)
 
@@ -313,7 +534,9 @@ const requestConnectionPermission = async (targetSS58Address, node, sessionState
       sessionState.outgoingPermissionRequests.set(requestId, {
         targetSS58Address,
         status: 'pending',
-        createdAt: Date.now()
+        createdAt: Date.now(),
+        // The relay's clock may differ from ours, so only trust the remaining lifetime it reports
+        expiresAt: Number.isFinite(parsed.expiresIn) ? Date.now() + parsed.expiresIn : undefined
       })
       appendOutput(`Permission request sent. Request ID: ${requestId}`)
       return requestId
@@ -403,6 +626,45 @@ const respondToPermissionRequest = async (requestId, accepted, node, sessionStat
   }
 }
 
+const cancelPermissionRequest = async (requestId, node, sessionState) => {
+  const relayConnection = getRelayConnection(node)
+  if (!relayConnection) {
+    throw new Error('No relay connection found')
+  }
+
+  const stream = await node.dialProtocol(relayConnection.remoteAddr, CONNECTION_PERMISSION_PROTOCOL, {
+    signal: AbortSignal.timeout(STREAM_TIMEOUT)
+  })
+
+  try {
+    const streamWriter = byteStream(stream)
+    const streamReader = byteStream(stream)
+
+    const request = { action: 'cancel', requestId }
+    const message = JSON.stringify(request)
+
+    await streamWriter.write(fromString(message))
+    const response = await streamReader.read()
+
+    if (response === null) {
+      throw new Error('No response from relay')
+    }
+
+    const responseText = toString(response.subarray())
+    const parsed = JSON.parse(responseText)
+
+    if (parsed.success) {
+      sessionState.outgoingPermissionRequests.delete(requestId)
+      appendOutput(`Permission request ${requestId} cancelled`)
+      return true
+    } else {
+      throw new Error(`Cancel failed: ${parsed.error}`)
+    }
+  } finally {
+    await stream.close()
+  }
+}
+
 const checkForIncomingPermissionRequests = async (node, sessionState) => {
   if (!sessionState.mySS58Address) return
 
@@ -442,6 +704,16 @@ const checkForIncomingPermissionRequests = async (node, sessionState) => {
         }
       }
     }
+
+    if (parsed.success && parsed.cancelledRequestIds) {
+      for (const requestId of parsed.cancelledRequestIds) {
+        if (sessionState.pendingPermissionRequests.has(requestId)) {
+          sessionState.pendingPermissionRequests.delete(requestId)
+          removePermissionRequestUI(requestId)
+          appendOutput(`Connection request ${requestId} was cancelled by the requester`)
+        }
+      }
+    }
   } finally {
     await stream.close()
   }
@@ -481,24 +753,28 @@ const displayPermissionRequest = (requestId, requesterSS58Address) => {
   appendOutput(`New connection request from ${requesterSS58Address} (ID: ${requestId})`)
 }
 
+const removePermissionRequestUI = (requestId) => {
+  // Remove the specific permission request UI
+  const permissionDiv = document.getElementById(`permission-request-${requestId}`)
+  if (permissionDiv) {
+    permissionDiv.remove()
+  }
+
+  // If no more permission requests, show placeholder
+  const permissionRequestsContainer = document.getElementById('permission-requests')
+  const remainingRequests = permissionRequestsContainer.querySelectorAll('.permission-request')
+  if (remainingRequests.length === 0) {
+    const placeholder = document.createElement('p')
+    placeholder.textContent = 'Incoming connection requests will appear here. You can accept or reject them.'
+    permissionRequestsContainer.appendChild(placeholder)
+  }
+}
+
 // Global functions for permission request buttons
 window.acceptPermissionRequest = async (requestId) => {
   try {
     await respondToPermissionRequest(requestId, true, node, sessionState)
-    // Remove the specific permission request UI
-    const permissionDiv = document.getElementById(`permission-request-${requestId}`)
-    if (permissionDiv) {
-      permissionDiv.remove()
-    }
-
-    // If no more permission requests, show placeholder
-    const permissionRequestsContainer = document.getElementById('permission-requests')
-    const remainingRequests = permissionRequestsContainer.querySelectorAll('.permission-request')
-    if (remainingRequests.length === 0) {
-      const placeholder = document.createElement('p')
-      placeholder.textContent = 'Incoming connection requests will appear here. You can accept or reject them.'
-      permissionRequestsContainer.appendChild(placeholder)
-    }
+    removePermissionRequestUI(requestId)
   } catch (error) {
     appendOutput(`Error accepting permission request: ${error.message}`)
   }
@@ -507,20 +783,7 @@ window.acceptPermissionRequest = async (requestId) => {
 window.rejectPermissionRequest = async (requestId) => {
   try {
     await respondToPermissionRequest(requestId, false, node, sessionState)
-    // Remove the specific permission request UI
-    const permissionDiv = document.getElementById(`permission-request-${requestId}`)
-    if (permissionDiv) {
-      permissionDiv.remove()
-    }
-
-    // If no more permission requests, show placeholder
-    const permissionRequestsContainer = document.getElementById('permission-requests')
-    const remainingRequests = permissionRequestsContainer.querySelectorAll('.permission-request')
-    if (remainingRequests.length === 0) {
-      const placeholder = document.createElement('p')
-      placeholder.textContent = 'Incoming connection requests will appear here. You can accept or reject them.'
-      permissionRequestsContainer.appendChild(placeholder)
-    }
+    removePermissionRequestUI(requestId)
   } catch (error) {
     appendOutput(`Error rejecting permission request: ${error.message}`)
   }
@@ -530,6 +793,7 @@ window.rejectPermissionRequest = async (requestId) => {
 let node = null
 let sessionId = null
 let permissionRequestInterval = null
+let heartbeatInterval = null
 
 // Initialize a new session with unique Peer ID
 const initializeSession = async () => {
@@ -577,6 +841,9 @@ const initializeSession = async () => {
     // Start periodic checking for incoming permission requests
     startPermissionRequestPolling()
 
+    // Start heartbeats to verified peers
+    startHeartbeats()
+
     appendOutput(`Session initialized successfully`)
 
   } catch (error) {
@@ -633,15 +900,9 @@ const setupEventListeners = () => {
 // Set up protocol handlers
 const setupProtocolHandlers = () => {
   // Chat Protocol Handler
-  node.handle(CHAT_PROTOCOL, async ({ stream }) => {
+  node.handle(CHAT_PROTOCOL, async ({ stream, connection }) => {
     sessionState.chatStream = byteStream(stream)
-    while (true) {
-      const buffer = await sessionState.chatStream.read()
-      if (buffer === null) {
-        break // End of stream
-      }
-      appendOutput(`Received: '${toString(buffer.subarray())}'`)
-    }
+    await readChatStream(sessionState.chatStream, connection.remotePeer.toString())
   })
 
   // Connection Challenge Protocol Handler
@@ -687,6 +948,76 @@ const startPermissionRequestPolling = () => {
   }, 10000) // Check every 10 seconds
 }
 
+// Presence Tracking
+// A peer is 'online' when its last heartbeat was answered quickly, 'slow' when heartbeats are
+// late or missed but the connection is still open, and 'offline' once the connection is gone
+// or too many heartbeats in a row went unanswered
+const getPresenceStatus = (presence, connected) => {
+  if (!connected || presence.missedHeartbeats >= OFFLINE_AFTER_MISSED_HEARTBEATS) {
+    return 'offline'
+  }
+  if (presence.missedHeartbeats > 0 || presence.rtt === null || presence.rtt > SLOW_PEER_RTT) {
+    return 'slow'
+  }
+  return 'online'
+}
+
+const sendHeartbeats = async () => {
+  if (!node) return
+
+  // Ping all peers at once so a run always finishes within HEARTBEAT_TIMEOUT, before the next one starts
+  await Promise.all(Array.from(sessionState.verifiedPeers, async ([peerId, ss58Address]) => {
+    const presence = sessionState.peerPresence.get(peerId) ||
+      { ss58Address, lastSeen: null, rtt: null, missedHeartbeats: 0 }
+    sessionState.peerPresence.set(peerId, presence)
+    const connection = node.getConnections().find(conn => conn.remotePeer.toString() === peerId)
+
+    if (!connection) {
+      // Don't redial an offline peer just to ping it
+      presence.missedHeartbeats++
+      return
+    }
+
+    try {
+      presence.rtt = await node.services.ping.ping(connection.remotePeer, {
+        signal: AbortSignal.timeout(HEARTBEAT_TIMEOUT)
+      })
+      presence.lastSeen = Date.now()
+      presence.missedHeartbeats = 0
+    } catch (error) {
+      presence.missedHeartbeats++
+    }
+  }))
+
+  updatePresenceList()
+}
+
+const getPeerPresence = () => {
+  const connectedPeers = new Set(node ? node.getConnections().map(conn => conn.remotePeer.toString()) : [])
+
+  return Array.from(sessionState.peerPresence.entries()).map(([peerId, presence]) => ({
+    peerId,
+    ss58Address: presence.ss58Address,
+    status: getPresenceStatus(presence, connectedPeers.has(peerId)),
+    lastSeen: presence.lastSeen,
+    rtt: presence.rtt
+  }))
+}
+
+// Expose presence to the page (e.g. for coordinators deciding whether to wait for a peer)
+window.getPeerPresence = getPeerPresence
+
+// Start periodic heartbeats to verified peers
+const startHeartbeats = () => {
+  heartbeatInterval = setInterval(async () => {
+    try {
+      await sendHeartbeats()
+    } catch (error) {
+      // Presence is best effort; the next heartbeat will retry
+    }
+  }, HEARTBEAT_INTERVAL)
+}
+
 // Clean up session resources
 const cleanupSession = async () => {
   try {
@@ -696,6 +1027,12 @@ const cleanupSession = async () => {
       permissionRequestInterval = null
     }
 
+    // Stop heartbeats
+    if (heartbeatInterval) {
+      clearInterval(heartbeatInterval)
+      heartbeatInterval = null
+    }
+
     // Close chat stream
     if (sessionState.chatStream) {
       try {
@@ -733,6 +1070,7 @@ const updateConnList = () => {
   const connectionElements = node.getConnections().map((connection) => {
     if (WebRTC.matches(connection.remoteAddr)) {
       sessionState.peerMultiaddr = connection.remoteAddr
+      sessionState.peerId = connection.remotePeer.toString()
       sendSection.style.display = 'block'
     }
     const element = document.createElement('li')
@@ -755,8 +1093,165 @@ const updateMultiaddrs = () => {
   document.getElementById('multiaddrs').replaceChildren(...webrtcMultiaddrs)
 }
 
+const updatePresenceList = () => {
+  const presenceElements = getPeerPresence().map((presence) => {
+    const element = document.createElement('li')
+    const lastSeen = presence.lastSeen ? new Date(presence.lastSeen).toLocaleTimeString() : 'never'
+    const rtt = presence.rtt !== null ? `, ${presence.rtt} ms` : ''
+    element.textContent = `${presence.ss58Address}: ${presence.status} (last seen ${lastSeen}${rtt})`
+    return element
+  })
+  document.getElementById('peer-presence').replaceChildren(...presenceElements)
+}
+
 
 // Chat Stream Management
+const handleIncomingMessage = async (frame, remotePeerId) => {
+  const expectedSender = sessionState.verifiedPeers.get(remotePeerId)
+  if (!expectedSender) {
+    appendOutput('Rejected message: peer has not completed connection proof of possession')
+    return
+  }
+
+  let envelope
+  let isNew
+  try {
+    envelope = decodeEnvelope(frame, expectedSender)
+    if (envelope.kind === MESSAGE_KIND_ACK) {
+      // Acknowledgements reuse the acknowledged sequence number, and a repeated one is harmless
+      checkEnvelopeSession(envelope, remotePeerId)
+    } else {
+      isNew = checkEnvelopeReplay(envelope, remotePeerId, sessionState)
+    }
+  } catch (error) {
+    appendOutput(`Rejected message: ${error.message}`)
+    return
+  }
+
+  if (envelope.kind === MESSAGE_KIND_ACK) {
+    handleAcknowledgement(envelope.sequence, remotePeerId)
+  } else if (envelope.kind === MESSAGE_KIND_CHAT) {
+    await sendAcknowledgement(envelope.sequence, remotePeerId)
+    if (isNew) {
+      appendOutput(`Received: '${envelope.payload}'`)
+    } else {
+      appendOutput(`Ignored duplicate message ${envelope.sequence}`)
+    }
+  } else {
+    appendOutput(`Rejected message: unknown kind '${envelope.kind}'`)
+  }
+}
+
+// A read may hold part of an envelope or several envelopes, so buffer until a frame delimiter arrives
+const readChatStream = async (chatStream, remotePeerId) => {
+  const decoder = new TextDecoder()
+  let pending = ''
+  while (true) {
+    const buffer = await chatStream.read()
+    if (buffer === null) {
+      break // End of stream
+    }
+    pending += decoder.decode(buffer.subarray(), { stream: true })
+
+    let delimiterIndex
+    while ((delimiterIndex = pending.indexOf(FRAME_DELIMITER)) !== -1) {
+      const frame = pending.slice(0, delimiterIndex)
+      pending = pending.slice(delimiterIndex + 1)
+      await handleIncomingMessage(frame, remotePeerId)
+    }
+
+    if (pending.length > MAX_FRAME_SIZE) {
+      appendOutput(`Rejected message: larger than ${MAX_FRAME_SIZE} bytes`)
+      pending = ''
+    }
+  }
+}
+
+// Acknowledge a received envelope so the sender stops resending it
+const sendAcknowledgement = async (sequence, remotePeerId) => {
+  try {
+    const { data } = encodeEnvelope(MESSAGE_KIND_ACK, null, remotePeerId, sessionState, sequence)
+    await sessionState.chatStream.write(data)
+  } catch (error) {
+    // The sender resends the envelope and we acknowledge it again
+  }
+}
+
+const pendingEnvelopeKey = (remotePeerId, sequence) => `${remotePeerId}:${sequence}`
+
+const handleAcknowledgement = (sequence, remotePeerId) => {
+  const key = pendingEnvelopeKey(remotePeerId, sequence)
+  const pending = sessionState.unacknowledgedEnvelopes.get(key)
+  if (!pending) return
+
+  clearTimeout(pending.timer)
+  sessionState.unacknowledgedEnvelopes.delete(key)
+}
+
+// Write an envelope and schedule a resend with exponential backoff in case it is not acknowledged
+const transmitEnvelope = async (sequence, remotePeerId) => {
+  const pending = sessionState.unacknowledgedEnvelopes.get(pendingEnvelopeKey(remotePeerId, sequence))
+  if (!pending) return // Acknowledged in the meantime
+
+  pending.attempts++
+  try {
+    if (await handleChatStream()) {
+      if (lostChatWrites > 0) {
+        lostChatWrites-- // Lost in transit: the stream stays open and only the resend delivers it
+      } else {
+        await sessionState.chatStream.write(pending.data)
+      }
+    }
+  } catch (error) {
+    // The stream is broken; close it so its reader stops, and let the resend open a new one
+    const brokenStream = sessionState.chatStream
+    sessionState.chatStream = null
+    brokenStream?.close().catch(() => {
+      // Stream might already be closed
+    })
+  }
+
+  pending.timer = setTimeout(() => resendEnvelope(sequence, remotePeerId), RESEND_BASE_DELAY * 2 ** (pending.attempts - 1))
+}
+
+// Test hook, only compiled in when the dev server runs with VITE_TEST_HOOKS=true
+if (import.meta.env.VITE_TEST_HOOKS === 'true') {
+  window.loseChatWrites = (count = 1) => {
+    lostChatWrites = count
+  }
+
+  // Write a hand-built chat envelope on the open stream: `fields` are changed before it is signed
+  // and `tampered` after, and it is not resent. Returns its sequence number
+  window.sendTestEnvelope = async ({ payload, fields = {}, tampered = {} }) => {
+    const envelope = signEnvelope({
+      version: sessionState.envelopeVersions.get(sessionState.peerId),
+      kind: MESSAGE_KIND_CHAT,
+      sessionId,
+      sequence: nextSendSequence(sessionState.peerId, sessionState),
+      sender: sessionState.mySS58Address,
+      payload,
+      ...fields
+    }, sessionState.myKeyPair)
+    await sessionState.chatStream.write(serializeEnvelope({ ...envelope, ...tampered }))
+    return envelope.sequence
+  }
+}
+
+const resendEnvelope = async (sequence, remotePeerId) => {
+  const key = pendingEnvelopeKey(remotePeerId, sequence)
+  const pending = sessionState.unacknowledgedEnvelopes.get(key)
+  if (!pending) return
+
+  if (pending.attempts >= MAX_SEND_ATTEMPTS) {
+    sessionState.unacknowledgedEnvelopes.delete(key)
+    appendOutput(`Message ${sequence} was not acknowledged after ${MAX_SEND_ATTEMPTS} attempts`)
+    return
+  }
+
+  appendOutput(`Resending message ${sequence} (attempt ${pending.attempts + 1} of ${MAX_SEND_ATTEMPTS})`)
+  await transmitEnvelope(sequence, remotePeerId)
+}
+
 const handleChatStream = async () => {
   if (sessionState.chatStream == null) {
     appendOutput('Opening chat stream')
@@ -766,15 +1261,7 @@ const handleChatStream = async () => {
       sessionState.chatStream = byteStream(stream)
 
       // Handle incoming messages
-      Promise.resolve().then(async () => {
-        while (true) {
-          const buffer = await sessionState.chatStream.read()
-          if (buffer === null) {
-            break // End of stream
-          }
-          appendOutput(`Received: '${toString(buffer.subarray())}'`)
-        }
-      })
+      Promise.resolve().then(() => readChatStream(sessionState.chatStream, sessionState.peerId))
     } catch (error) {
       if (signal.aborted) {
         appendOutput('Chat stream timeout')
@@ -790,7 +1277,10 @@ const handleChatStream = async () => {
 const sendMessage = async (message) => {
   appendOutput(`Sending: '${message}'`)
   try {
-    await sessionState.chatStream.write(fromString(message))
+    const remotePeerId = sessionState.peerId
+    const { sequence, data } = encodeEnvelope(MESSAGE_KIND_CHAT, message, remotePeerId, sessionState)
+    sessionState.unacknowledgedEnvelopes.set(pendingEnvelopeKey(remotePeerId, sequence), { data, attempts: 0, timer: null })
+    await transmitEnvelope(sequence, remotePeerId)
   } catch (error) {
     appendOutput(`Send error: ${error.message}`)
   }
@@ -898,8 +1388,8 @@ const getWebrtcMultiaddr = (node) => {
   return multiaddrs.find(multiaddr => WebRTC.matches(multiaddr))
 }
 
-const storeAddressInRelay = async (polkadotAddress, webrtcMultiaddr, secretKey, node, sessionState) => {
-  if (!secretKey) {
+const storeAddressInRelay = async (polkadotAddress, webrtcMultiaddr, secretKey, keyPair, node, sessionState) => {
+  if (!secretKey || !keyPair) {
     throw new Error('Secret key is required for proof of possession')
   }
 
@@ -909,13 +1399,14 @@ const storeAddressInRelay = async (polkadotAddress, webrtcMultiaddr, secretKey,
 
     // Step 2: Sign the challenge with the secret key
     appendOutput('Signing challenge...')
-    const signature = await signMessage(challenge, secretKey)
+    const signature = await signMessage(challenge, keyPair)
 
     // Step 3: Submit proof to relay
     await submitProof(polkadotAddress, challenge, signature, webrtcMultiaddr, node)
 
     sessionState.mySS58Address = polkadotAddress
     sessionState.mySecretKey = secretKey
+    sessionState.myKeyPair = keyPair
     appendOutput('Address registered with proof of possession!')
   } catch (error) {
     throw new Error(`Proof of possession failed: ${error.message}`)
@@ -943,11 +1434,14 @@ window['store-address-input'].onclick = async () => {
     appendOutput(`Valid address: ${polkadotAddress}`)
 
     appendOutput('Validating secret key...')
-    // Validate secret key format (should be 32 bytes = 64 hex chars + 0x prefix)
-    if (!secretKey.startsWith('0x') || secretKey.length !== 66) {
-      throw new Error('Secret key must be 32 bytes (64 hex characters) with 0x prefix')
+    // Accept a 32-byte hex seed or a secret URI, and make sure it controls the address
+    const keyPair = await keyPairFromSecret(secretKey)
+    if (!u8aEq(keyPair.publicKey, decodeAddress(polkadotAddress))) {
+      throw new Error('Secret key does not match the SS58 address')
     }
-    appendOutput(`Valid secret key: ${secretKey.substring(0, 10)}...`)
+    appendOutput(isHex(secretKey, 256)
+      ? `Valid secret key: ${secretKey.substring(0, 10)}...`
+      : 'Valid secret URI')
 
     const webrtcMultiaddr = getWebrtcMultiaddr(node)
     if (!webrtcMultiaddr) {
@@ -956,7 +1450,7 @@ window['store-address-input'].onclick = async () => {
       return
     }
 
-    await storeAddressInRelay(polkadotAddress, webrtcMultiaddr, secretKey, node, sessionState)
+    await storeAddressInRelay(polkadotAddress, webrtcMultiaddr, secretKey, keyPair, node, sessionState)
   } catch (error) {
     appendOutput(`Error: ${error.message}`)
   }
@@ -1003,7 +1497,7 @@ const queryRelayForAddress = async (polkadotAddress, node) => {
   }
 }
 
-const connectToPeer = async (peerMultiaddrString, node, sessionState) => {
+const connectToPeer = async (peerMultiaddrString, peerSS58Address, node, sessionState) => {
   appendOutput('Connecting to peer...')
   try {
     const dialSignal = AbortSignal.timeout(CONNECTION_TIMEOUT)
@@ -1012,7 +1506,7 @@ const connectToPeer = async (peerMultiaddrString, node, sessionState) => {
     appendOutput('Connected to peer!')
 
     // Perform connection proof of possession
-    await performConnectionProofOfPossession(peerMultiaddr, node, sessionState)
+    await performConnectionProofOfPossession(peerMultiaddr, peerSS58Address, node, sessionState)
   } catch (error) {
     if (error.name === 'AbortError') {
       throw new Error('Connection timeout')
@@ -1032,12 +1526,11 @@ const connectToPeerWithPermission = async (targetSS58Address, node, sessionState
     const requestId = await requestConnectionPermission(targetSS58Address, node, sessionState)
     appendOutput(`Waiting for permission from ${targetSS58Address}...`)
 
-    // Step 2: Poll for permission status
+    // Step 2: Poll for permission status until the relay expires the request
     let permissionGranted = false
-    let attempts = 0
-    const maxAttempts = 60 // 5 minutes with 5-second intervals
+    const expiresAt = sessionState.outgoingPermissionRequests.get(requestId)?.expiresAt ?? Date.now() + PERMISSION_REQUEST_TIMEOUT
 
-    while (!permissionGranted && attempts < maxAttempts) {
+    while (!permissionGranted && Date.now() < expiresAt) {
       await new Promise(resolve => setTimeout(resolve, 5000)) // Wait 5 seconds
 
       try {
@@ -1047,6 +1540,8 @@ const connectToPeerWithPermission = async (targetSS58Address, node, sessionState
           appendOutput(`Permission granted! Proceeding with connection...`)
         } else if (status.status === 'rejected') {
           throw new Error('Connection permission was rejected')
+        } else if (status.status === 'cancelled') {
+          throw new Error('Permission request was cancelled')
         }
         // If still pending, continue waiting
       } catch (error) {
@@ -1055,11 +1550,15 @@ const connectToPeerWithPermission = async (targetSS58Address, node, sessionState
         }
         throw error
       }
-
-      attempts++
     }
 
     if (!permissionGranted) {
+      // Abort the request on the relay so the target stops seeing it
+      try {
+        await cancelPermissionRequest(requestId, node, sessionState)
+      } catch (error) {
+        // The request may already have expired on the relay
+      }
       throw new Error('Permission request timed out')
     }
 
@@ -1068,7 +1567,7 @@ const connectToPeerWithPermission = async (targetSS58Address, node, sessionState
     appendOutput(`Found peer address: ${peerMultiaddrString}`)
 
     // Step 4: Connect to the peer
-    await connectToPeer(peerMultiaddrString, node, sessionState)
+    await connectToPeer(peerMultiaddrString, targetSS58Address, node, sessionState)
 
     // Clean up the outgoing request
     sessionState.outgoingPermissionRequests.delete(requestId)
@@ -1076,11 +1575,14 @@ const connectToPeerWithPermission = async (targetSS58Address, node, sessionState
   } catch (error) {
     appendOutput(`Permission-based connection failed: ${error.message}`)
     throw error
+  } finally {
+    // The next attempt is a new submission
+    sessionState.permissionRequestKeys.delete(targetSS58Address)
   }
 }
 
 // Perform connection proof of possession
-const performConnectionProofOfPossession = async (peerMultiaddr, node, sessionState) => {
+const performConnectionProofOfPossession = async (peerMultiaddr, peerSS58Address, node, sessionState) => {
   if (!sessionState.mySS58Address || !sessionState.mySecretKey) {
     throw new Error('No SS58 address or secret key available for connection proof of possession')
   }
@@ -1096,8 +1598,8 @@ const performConnectionProofOfPossession = async (peerMultiaddr, node, sessionSt
     const streamWriter = byteStream(stream)
     const streamReader = byteStream(stream)
 
-    // Step 2: Request challenge from peer
-    const initiateRequest = { action: 'initiate' }
+    // Step 2: Request challenge from peer, advertising our envelope versions
+    const initiateRequest = { action: 'initiate', envelopeVersions: SUPPORTED_ENVELOPE_VERSIONS }
     await streamWriter.write(fromString(JSON.stringify(initiateRequest)))
 
     const challengeResponse = await streamReader.read()
@@ -1111,10 +1613,16 @@ const performConnectionProofOfPossession = async (peerMultiaddr, node, sessionSt
     }
 
     const challenge = challengeData.challenge
+    const myPeerId = node.peerId.toString()
+    const remotePeerId = peerMultiaddr.getPeerId()
+    if (challengeData.envelopeVersion === undefined) {
+      throw new Error(`Peer did not report a message envelope version; it may be running an older client (supported: ${SUPPORTED_ENVELOPE_VERSIONS.join(', ')})`)
+    }
+    sessionState.envelopeVersions.set(remotePeerId, negotiateEnvelopeVersion([challengeData.envelopeVersion]))
     appendOutput(`Received challenge: ${challenge}`)
 
     // Step 3: Sign the challenge and respond
-    const signature = await signMessage(challenge, sessionState.mySecretKey)
+    const signature = await signMessage(connectionSigningMessage(challenge, myPeerId), sessionState.myKeyPair)
     const respondRequest = {
       action: 'respond',
       ss58Address: sessionState.mySS58Address,
@@ -1136,8 +1644,9 @@ const performConnectionProofOfPossession = async (peerMultiaddr, node, sessionSt
 
     appendOutput('Our signature verified!')
 
-    // Step 4: Request mutual challenge from peer
-    const challengeRequest = { action: 'challenge' }
+    // Step 4: Challenge the peer to prove it controls the address we looked up, and request its challenge
+    const ourChallenge = generateConnectionChallenge()
+    const challengeRequest = { action: 'challenge', challenge: ourChallenge }
     await streamWriter.write(fromString(JSON.stringify(challengeRequest)))
 
     const mutualChallengeResponse = await streamReader.read()
@@ -1150,11 +1659,21 @@ const performConnectionProofOfPossession = async (peerMultiaddr, node, sessionSt
       throw new Error(`Mutual challenge request failed: ${mutualChallengeData.error}`)
     }
 
+    if (!mutualChallengeData.signature) {
+      throw new Error('Peer did not sign our challenge; it may be running an older client')
+    }
+    const peerSignatureValid = await verifyConnectionSignature(
+      peerSS58Address, connectionSigningMessage(ourChallenge, remotePeerId), mutualChallengeData.signature)
+    if (!peerSignatureValid) {
+      throw new Error(`Peer could not prove it controls ${peerSS58Address}`)
+    }
+    appendOutput('Peer signature verified!')
+
     const mutualChallenge = mutualChallengeData.challenge
     appendOutput(`Received mutual challenge: ${mutualChallenge}`)
 
     // Step 5: Sign the mutual challenge and verify
-    const mutualSignature = await signMessage(mutualChallenge, sessionState.mySecretKey)
+    const mutualSignature = await signMessage(connectionSigningMessage(mutualChallenge, myPeerId), sessionState.myKeyPair)
     const verifyRequest = {
       action: 'verify',
       ss58Address: sessionState.mySS58Address,
@@ -1174,6 +1693,8 @@ const performConnectionProofOfPossession = async (peerMultiaddr, node, sessionSt
       throw new Error(`Mutual signature verification failed: ${verifyData.error}`)
     }
 
+    // The peer signed our challenge for its own Peer ID with the key of the address we looked up
+    sessionState.verifiedPeers.set(remotePeerId, peerSS58Address)
     appendOutput('Mutual connection proof of possession completed!')
 
   } finally {
@@ -11,9 +11,13 @@ import { byteStream } from 'it-byte-stream'
 import { fromString, toString } from 'uint8arrays'
 import { peerIdFromString } from '@libp2p/peer-id'
 import fetch from 'node-fetch'
+import http from 'node:http'
+import { createHash, createHmac, timingSafeEqual } from 'node:crypto'
+import { appendFileSync, closeSync, fstatSync, openSync, readSync } from 'node:fs'
+import { EventEmitter } from 'node:events'
 import { cryptoWaitReady, sr25519Verify, sr25519PairFromSeed } from '@polkadot/util-crypto'
 import { decodeAddress, encodeAddress } from '@polkadot/keyring'
-import { hexToU8a } from '@polkadot/util'
+import { hexToU8a, u8aWrapBytes } from '@polkadot/util'
 
 // Constants
 const KV_PROTOCOL = '/libp2p/examples/kv/1.0.0'
@@ -24,10 +28,31 @@ const CONNECTION_PERMISSION_PROTOCOL = '/libp2p/examples/connection-permission/1
 const HARDCODED_PEER_ID = '12D3KooWA1bysjrTACSWqf6q172inxvwKHUxAnBtVgaVDKMxpZtx'
 const EXTERNAL_PORT = '8080'
 const MAX_RESERVATIONS = Infinity
+const CLEANUP_INTERVAL = 60 * 1000 // Sweep expired challenges and permission requests every minute
+const PERMISSION_REQUEST_TTL = 10 * 60 * 1000 // Default and maximum permission request lifetime (10 minutes)
+const MIN_PERMISSION_REQUEST_TTL = 30 * 1000
+const DEFAULT_METRICS_PORT = '9464'
+const DEFAULT_METRICS_HOST = '127.0.0.1' // Metrics stay off the public interface unless METRICS_HOST says otherwise
+
+// Metrics Histogram Buckets
+const MESSAGE_SIZE_BUCKETS = [64, 256, 1024, 4096, 16384] // Bytes
+const PERMISSION_RESPONSE_BUCKETS = [1, 5, 15, 60, 300, 600] // Seconds
 
 // Error Codes
 const STREAM_ABORT_ERROR = 'ERR_STREAM_ABORT'
 
+// Rejection Statuses (HTTP-style)
+const STATUS_TOO_MANY_REQUESTS = 429
+const STATUS_PAYLOAD_TOO_LARGE = 413
+
+// Default Rate Limits
+const DEFAULT_RATE_LIMITS = {
+  messagesPerSecond: 10, // Sustained messages per second per connection
+  burstSize: 20, // Messages a connection may send in a burst
+  maxMessageSize: 16 * 1024, // Bytes
+  maxRegistrationsPerIp: 10 // Addresses a single IP may register
+}
+
 // Default Key-Value Store
 const kvStore = new Map()
 
@@ -37,6 +62,45 @@ const challengeStore = new Map()
 // Permission request store for connection permissions
 const permissionRequests = new Map()
 
+// Idempotency key index for permission requests (requester peer ID + key -> request ID)
+const permissionRequestIdsByKey = new Map()
+
+// Metrics
+const createHistogram = (buckets) => ({
+  buckets,
+  counts: buckets.map(() => 0),
+  sum: 0,
+  count: 0
+})
+
+const observe = (histogram, value) => {
+  histogram.buckets.forEach((bucket, index) => {
+    if (value <= bucket) {
+      histogram.counts[index]++
+    }
+  })
+  histogram.sum += value
+  histogram.count++
+}
+
+const relayMetrics = {
+  registrations: { verified: 0, failed: 0 },
+  permissionRequests: { created: 0, accepted: 0, rejected: 0, cancelled: 0, expired: 0 },
+  messageBytes: createHistogram(MESSAGE_SIZE_BUCKETS),
+  permissionResponseSeconds: createHistogram(PERMISSION_RESPONSE_BUCKETS)
+}
+
+// Relay lifecycle events (address registrations and permission request outcomes)
+export const relayEvents = new EventEmitter()
+
+const emitPermissionEvent = (event, requestId, permissionRequest) => {
+  relayEvents.emit(event, {
+    requestId,
+    requesterSS58Address: permissionRequest.requesterSS58Address,
+    targetSS58Address: permissionRequest.targetSS58Address
+  })
+}
+
 // Utility Functions
 const logInfo = (message) => console.log(message)
 const logError = (message) => console.log(`ERROR: ${message}`)
@@ -74,8 +138,16 @@ const validateSS58Address = (address) => {
   }
 }
 
+// Verify a signature over the message or over its <Bytes>-wrapped form (as produced by polkadot-js signRaw)
+const verifyWithBytesWrapping = (messageBytes, signatureBytes, publicKey, requireBytesWrapping) => {
+  if (sr25519Verify(u8aWrapBytes(messageBytes), signatureBytes, publicKey)) {
+    return true
+  }
+  return !requireBytesWrapping && sr25519Verify(messageBytes, signatureBytes, publicKey)
+}
+
 // Verify signature for proof of possession
-const verifyProofOfPossession = async (ss58Address, challenge, signature) => {
+const verifyProofOfPossession = async (ss58Address, challenge, signature, requireBytesWrapping = false) => {
   try {
     await initializeCrypto()
 
@@ -94,7 +166,7 @@ const verifyProofOfPossession = async (ss58Address, challenge, signature) => {
     const publicKey = decodeAddress(ss58Address)
 
     // Verify the signature
-    const isValid = sr25519Verify(challengeBytes, signatureBytes, publicKey)
+    const isValid = verifyWithBytesWrapping(challengeBytes, signatureBytes, publicKey, requireBytesWrapping)
 
     return isValid
   } catch (error) {
@@ -108,15 +180,162 @@ const createSuccessResponse = (data = {}) => ({
   ...data
 })
 
-const createErrorResponse = (error) => ({
+const createErrorResponse = (error, status) => ({
   success: false,
-  error
+  error,
+  ...(status !== undefined && { status })
 })
 
 const writeResponse = async (streamWriter, response) => {
   await streamWriter.write(fromString(JSON.stringify(response)))
 }
 
+// Rate Limiting
+class RateLimitError extends Error {
+  constructor(message, status = STATUS_TOO_MANY_REQUESTS) {
+    super(message)
+    this.status = status
+  }
+}
+
+const getRemoteIp = (connection) => {
+  try {
+    return connection.remoteAddr.toOptions().host
+  } catch (error) {
+    return connection.remoteAddr.toString()
+  }
+}
+
+export const createRateLimiter = (limits = {}) => {
+  const config = { ...DEFAULT_RATE_LIMITS, ...limits }
+  const buckets = new Map() // connection id -> { tokens, refilledAt }
+  const registrationsByIp = new Map() // ip -> Set of registered keys
+  const ipByRegistration = new Map() // registered key -> ip it counts against
+  const metrics = {
+    rateLimited: 0,
+    oversized: 0,
+    registrationsRejected: 0
+  }
+
+  const takeToken = (connectionId) => {
+    const now = Date.now()
+    const bucket = buckets.get(connectionId) || { tokens: config.burstSize, refilledAt: now }
+
+    const elapsedSeconds = (now - bucket.refilledAt) / 1000
+    bucket.tokens = Math.min(config.burstSize, bucket.tokens + elapsedSeconds * config.messagesPerSecond)
+    bucket.refilledAt = now
+    buckets.set(connectionId, bucket)
+
+    if (bucket.tokens < 1) {
+      return false
+    }
+
+    bucket.tokens -= 1
+    return true
+  }
+
+  // Returns an error response if the message must be rejected, null otherwise
+  const checkMessage = (connection, data) => {
+    if (data.byteLength > config.maxMessageSize) {
+      metrics.oversized++
+      logError(`Rejected ${data.byteLength}-byte message from ${getRemoteIp(connection)} (max ${config.maxMessageSize})`)
+      return createErrorResponse(`Message too large (max ${config.maxMessageSize} bytes)`, STATUS_PAYLOAD_TOO_LARGE)
+    }
+
+    if (!takeToken(connection.id)) {
+      metrics.rateLimited++
+      logError(`Rate limited connection from ${getRemoteIp(connection)}`)
+      return createErrorResponse('Too many requests', STATUS_TOO_MANY_REQUESTS)
+    }
+
+    return null
+  }
+
+  const trackRegistration = (connection, key) => {
+    const ip = getRemoteIp(connection)
+    const registrations = registrationsByIp.get(ip) || new Set()
+
+    if (!registrations.has(key) && registrations.size >= config.maxRegistrationsPerIp) {
+      metrics.registrationsRejected++
+      throw new RateLimitError(`Too many registered addresses from this IP (max ${config.maxRegistrationsPerIp})`)
+    }
+
+    // A key re-registered from a new IP (e.g. a phone switching networks) stops counting against the old one
+    releaseRegistration(key)
+    registrations.add(key)
+    registrationsByIp.set(ip, registrations)
+    ipByRegistration.set(key, ip)
+  }
+
+  const releaseRegistration = (key) => {
+    const ip = ipByRegistration.get(key)
+    if (ip === undefined) return
+
+    ipByRegistration.delete(key)
+    const registrations = registrationsByIp.get(ip)
+    registrations.delete(key)
+    if (registrations.size === 0) {
+      registrationsByIp.delete(ip)
+    }
+  }
+
+  const releaseConnection = (connectionId) => {
+    buckets.delete(connectionId)
+  }
+
+  return {
+    config,
+    metrics,
+    checkMessage,
+    trackRegistration,
+    releaseRegistration,
+    releaseConnection
+  }
+}
+
+// Expired Entry Cleanup
+// Drop an expired permission request and its idempotency key, reporting it if nobody answered
+const expirePermissionRequest = (requestId, permissionRequest) => {
+  if (permissionRequest.status === 'pending') {
+    relayMetrics.permissionRequests.expired++
+    emitPermissionEvent('permission:expired', requestId, permissionRequest)
+  }
+  permissionRequests.delete(requestId)
+  permissionRequestIdsByKey.delete(permissionRequest.idempotencyIndexKey)
+}
+
+export const cleanupExpiredEntries = (now = Date.now()) => {
+  let removedChallenges = 0
+  let removedRequests = 0
+
+  for (const [ss58Address, storedChallenge] of challengeStore) {
+    if (now > storedChallenge.expiresAt) {
+      challengeStore.delete(ss58Address)
+      removedChallenges++
+    }
+  }
+
+  for (const [requestId, permissionRequest] of permissionRequests) {
+    if (now > permissionRequest.expiresAt) {
+      expirePermissionRequest(requestId, permissionRequest)
+      removedRequests++
+    }
+  }
+
+  if (removedChallenges > 0 || removedRequests > 0) {
+    logInfo(`Cleanup: removed ${removedChallenges} expired challenges and ${removedRequests} expired permission requests`)
+  }
+
+  return { removedChallenges, removedRequests }
+}
+
+const startCleanupTimer = (server) => {
+  const cleanupInterval = setInterval(() => cleanupExpiredEntries(), CLEANUP_INTERVAL)
+  cleanupInterval.unref?.() // Don't keep the process alive just for cleanup
+
+  server.addEventListener('stop', () => clearInterval(cleanupInterval))
+}
+
 // Server Configuration
 const createDefaultListenAddresses = (port) => [
   `/ip4/0.0.0.0/tcp/${port}/ws`,
@@ -154,14 +373,46 @@ export async function createRelayServer(options = {}) {
 }
 
 // KV Storage Handler Functions
-const processKvStorageRequest = async (kvPair, kvStore) => {
+// Peer ID that wrote a stored entry; entries without one can no longer be changed through the KV protocols
+const getOwnerPeerId = (storedData) => {
+  try {
+    return JSON.parse(storedData).ownerPeerId
+  } catch (error) {
+    return undefined
+  }
+}
+
+const isSS58Address = (key) => {
+  try {
+    decodeAddress(key)
+    return true
+  } catch (error) {
+    return false
+  }
+}
+
+export const processKvStorageRequest = async (kvPair, kvStore, connection, rateLimiter) => {
   if (!kvPair.key || kvPair.value === undefined) {
     throw new Error('Invalid format')
   }
 
+  // Peers find each other by SS58 address, so only proof of possession may map one to a multiaddr
+  if (isSS58Address(kvPair.key)) {
+    throw new Error('SS58 addresses can only be registered with proof of possession')
+  }
+
+  const remotePeerId = connection.remotePeer.toString()
+  const storedData = kvStore.get(kvPair.key)
+  if (storedData !== undefined && getOwnerPeerId(storedData) !== remotePeerId) {
+    throw new Error('Key was stored by another peer')
+  }
+
+  rateLimiter.trackRegistration(connection, kvPair.key)
+
   const storageData = {
     value: kvPair.value,
-    circuitRelay: kvPair.circuitRelay || null
+    circuitRelay: kvPair.circuitRelay || null,
+    ownerPeerId: remotePeerId
   }
 
   kvStore.set(kvPair.key, JSON.stringify(storageData))
@@ -170,22 +421,34 @@ const processKvStorageRequest = async (kvPair, kvStore) => {
   return createSuccessResponse({ message: 'Stored successfully' })
 }
 
-const handleKvStorageStream = async (streamReader, streamWriter, kvStore) => {
+const handleKvStorageStream = async (streamReader, streamWriter, kvStore, connection, rateLimiter) => {
   while (true) {
     const data = await streamReader.read()
     if (data === null) {
       break // End of stream
     }
 
+    observe(relayMetrics.messageBytes, data.byteLength)
+    const limitResponse = rateLimiter.checkMessage(connection, data)
+    if (limitResponse) {
+      await writeResponse(streamWriter, limitResponse)
+      continue
+    }
+
     const message = toString(data.subarray())
     let response
 
     try {
       const kvPair = JSON.parse(message)
-      response = await processKvStorageRequest(kvPair, kvStore)
-    } catch (parseError) {
-      logError(`Invalid JSON: ${message}`)
-      response = createErrorResponse('Invalid JSON')
+      response = await processKvStorageRequest(kvPair, kvStore, connection, rateLimiter)
+    } catch (error) {
+      if (error instanceof SyntaxError) {
+        logError(`Invalid JSON: ${message}`)
+        response = createErrorResponse('Invalid JSON')
+      } else {
+        logError(`KV storage error: ${error.message}`)
+        response = createErrorResponse(error.message, error.status)
+      }
     }
 
     await writeResponse(streamWriter, response)
@@ -193,13 +456,13 @@ const handleKvStorageStream = async (streamReader, streamWriter, kvStore) => {
 }
 
 // KV Storage Protocol Handler
-const setupKvStorageHandler = (server, kvStore) => {
+const setupKvStorageHandler = (server, kvStore, rateLimiter) => {
   server.handle(KV_PROTOCOL, async ({ stream, connection }) => {
     const streamReader = byteStream(stream)
     const streamWriter = byteStream(stream)
 
     try {
-      await handleKvStorageStream(streamReader, streamWriter, kvStore)
+      await handleKvStorageStream(streamReader, streamWriter, kvStore, connection, rateLimiter)
     } catch (error) {
       if (error.code !== STREAM_ABORT_ERROR) {
         logError(`KV stream error: ${error.message}`)
@@ -248,8 +511,14 @@ const processListQuery = (kvStore) => {
   })
 }
 
-const processDeleteQuery = (query, kvStore) => {
+const processDeleteQuery = (query, kvStore, connection, rateLimiter) => {
+  const storedData = kvStore.get(query.key)
+  if (storedData !== undefined && getOwnerPeerId(storedData) !== connection.remotePeer.toString()) {
+    throw new Error('Only the peer that stored a key may delete it')
+  }
+
   const deleted = kvStore.delete(query.key)
+  rateLimiter.releaseRegistration(query.key)
   logInfo(`Delete: ${query.key} (${deleted ? 'deleted' : 'not found'}) - ${kvStore.size} remaining`)
 
   return createSuccessResponse({
@@ -258,31 +527,38 @@ const processDeleteQuery = (query, kvStore) => {
   })
 }
 
-const processKvQuery = (query, kvStore) => {
+export const processKvQuery = (query, kvStore, connection, rateLimiter) => {
   if (query.action === 'get' && query.key) {
     return processGetQuery(query, kvStore)
   } else if (query.action === 'list') {
     return processListQuery(kvStore)
   } else if (query.action === 'delete' && query.key) {
-    return processDeleteQuery(query, kvStore)
+    return processDeleteQuery(query, kvStore, connection, rateLimiter)
   } else {
     throw new Error('Invalid format')
   }
 }
 
-const handleKvQueryStream = async (streamReader, streamWriter, kvStore) => {
+const handleKvQueryStream = async (streamReader, streamWriter, kvStore, connection, rateLimiter) => {
   while (true) {
     const data = await streamReader.read()
     if (data === null) {
       break // End of stream
     }
 
+    observe(relayMetrics.messageBytes, data.byteLength)
+    const limitResponse = rateLimiter.checkMessage(connection, data)
+    if (limitResponse) {
+      await writeResponse(streamWriter, limitResponse)
+      continue
+    }
+
     const message = toString(data.subarray())
     let response
 
     try {
       const query = JSON.parse(message)
-      response = processKvQuery(query, kvStore)
+      response = processKvQuery(query, kvStore, connection, rateLimiter)
     } catch (error) {
       if (error instanceof SyntaxError) {
         logError(`Invalid JSON query: ${message}`)
@@ -298,13 +574,13 @@ const handleKvQueryStream = async (streamReader, streamWriter, kvStore) => {
 }
 
 // KV Query Protocol Handler
-const setupKvQueryHandler = (server, kvStore) => {
+const setupKvQueryHandler = (server, kvStore, rateLimiter) => {
   server.handle(KV_QUERY_PROTOCOL, async ({ stream, connection }) => {
     const streamReader = byteStream(stream)
     const streamWriter = byteStream(stream)
 
     try {
-      await handleKvQueryStream(streamReader, streamWriter, kvStore)
+      await handleKvQueryStream(streamReader, streamWriter, kvStore, connection, rateLimiter)
     } catch (error) {
       if (error.code !== STREAM_ABORT_ERROR) {
         logError(`Query stream error: ${error.message}`)
@@ -340,7 +616,7 @@ const processChallengeRequest = (request) => {
   })
 }
 
-const processProofRequest = async (request, kvStore) => {
+const processProofRequest = async (request, kvStore, connection, rateLimiter, requireBytesWrapping) => {
   if (!request.ss58Address || !request.challenge || !request.signature) {
     throw new Error('SS58 address, challenge, and signature are required')
   }
@@ -364,12 +640,15 @@ const processProofRequest = async (request, kvStore) => {
   }
 
   // Verify the signature
-  const isValid = await verifyProofOfPossession(request.ss58Address, request.challenge, request.signature)
+  const isValid = await verifyProofOfPossession(request.ss58Address, request.challenge, request.signature, requireBytesWrapping)
 
   if (!isValid) {
     throw new Error('Invalid signature')
   }
 
+  // Enforce the per-IP registration limit before storing
+  rateLimiter.trackRegistration(connection, request.ss58Address)
+
   // Clean up the challenge
   challengeStore.delete(request.ss58Address)
 
@@ -377,6 +656,7 @@ const processProofRequest = async (request, kvStore) => {
   const storageData = {
     value: request.webrtcMultiaddr,
     circuitRelay: request.circuitRelay || null,
+    ownerPeerId: connection.remotePeer.toString(),
     proofOfPossession: {
       verified: true,
       verifiedAt: Date.now()
@@ -385,6 +665,7 @@ const processProofRequest = async (request, kvStore) => {
 
   kvStore.set(request.ss58Address, JSON.stringify(storageData))
   logInfo(`Stored with proof of possession: ${request.ss58Address} -> ${request.webrtcMultiaddr} (${kvStore.size} total)`)
+  relayEvents.emit('address:registered', { ss58Address: request.ss58Address })
 
   return createSuccessResponse({
     message: 'Address registered with proof of possession',
@@ -392,36 +673,51 @@ const processProofRequest = async (request, kvStore) => {
   })
 }
 
-const processProofOfPossessionRequest = async (request, kvStore) => {
+const processProofOfPossessionRequest = async (request, kvStore, connection, rateLimiter, requireBytesWrapping) => {
   if (request.action === 'challenge') {
     return processChallengeRequest(request)
   } else if (request.action === 'proof') {
-    return processProofRequest(request, kvStore)
+    try {
+      const response = await processProofRequest(request, kvStore, connection, rateLimiter, requireBytesWrapping)
+      relayMetrics.registrations.verified++
+      return response
+    } catch (error) {
+      relayMetrics.registrations.failed++
+      relayEvents.emit('registration:failed', { ss58Address: request.ss58Address, reason: error.message })
+      throw error
+    }
   } else {
     throw new Error('Invalid action. Must be "challenge" or "proof"')
   }
 }
 
-const handleProofOfPossessionStream = async (streamReader, streamWriter, kvStore) => {
+const handleProofOfPossessionStream = async (streamReader, streamWriter, kvStore, connection, rateLimiter, requireBytesWrapping) => {
   while (true) {
     const data = await streamReader.read()
     if (data === null) {
       break // End of stream
     }
 
+    observe(relayMetrics.messageBytes, data.byteLength)
+    const limitResponse = rateLimiter.checkMessage(connection, data)
+    if (limitResponse) {
+      await writeResponse(streamWriter, limitResponse)
+      continue
+    }
+
     const message = toString(data.subarray())
     let response
 
     try {
       const request = JSON.parse(message)
-      response = await processProofOfPossessionRequest(request, kvStore)
+      response = await processProofOfPossessionRequest(request, kvStore, connection, rateLimiter, requireBytesWrapping)
     } catch (error) {
       if (error instanceof SyntaxError) {
         logError(`Invalid JSON: ${message}`)
         response = createErrorResponse('Invalid JSON')
       } else {
         logError(`Proof of possession error: ${error.message}`)
-        response = createErrorResponse(error.message)
+        response = createErrorResponse(error.message, error.status)
       }
     }
 
@@ -430,13 +726,13 @@ const handleProofOfPossessionStream = async (streamReader, streamWriter, kvStore
 }
 
 // Proof of Possession Protocol Handler
-const setupProofOfPossessionHandler = (server, kvStore) => {
+const setupProofOfPossessionHandler = (server, kvStore, rateLimiter, requireBytesWrapping) => {
   server.handle(PROOF_OF_POSSESSION_PROTOCOL, async ({ stream, connection }) => {
     const streamReader = byteStream(stream)
     const streamWriter = byteStream(stream)
 
     try {
-      await handleProofOfPossessionStream(streamReader, streamWriter, kvStore)
+      await handleProofOfPossessionStream(streamReader, streamWriter, kvStore, connection, rateLimiter, requireBytesWrapping)
     } catch (error) {
       if (error.code !== STREAM_ABORT_ERROR) {
         logError(`Proof of possession stream error: ${error.message}`)
@@ -446,10 +742,32 @@ const setupProofOfPossessionHandler = (server, kvStore) => {
 }
 
 // Connection Permission Handler Functions
-const processPermissionRequest = async (request, kvStore) => {
+export const processPermissionRequest = async (request, kvStore, connection) => {
   if (request.action === 'request') {
     // Peer A wants to connect to Peer B - forward the request
-    const { targetSS58Address, requesterSS58Address, requesterPeerId } = request
+    const { targetSS58Address, requesterSS58Address, requesterPeerId, idempotencyKey, expiresIn } = request
+
+    // A retried submission with the same idempotency key returns the existing request
+    const idempotencyIndexKey = idempotencyKey ? `${connection.remotePeer.toString()}:${idempotencyKey}` : undefined
+    const existingRequestId = idempotencyIndexKey && permissionRequestIdsByKey.get(idempotencyIndexKey)
+    const existingRequest = existingRequestId && permissionRequests.get(existingRequestId)
+    if (existingRequest && Date.now() > existingRequest.expiresAt) {
+      // Expired but not swept yet; the submission creates a new request below
+      expirePermissionRequest(existingRequestId, existingRequest)
+    } else if (existingRequest) {
+      if (existingRequest.targetSS58Address !== targetSS58Address) {
+        return createErrorResponse('Idempotency key was already used for a different target')
+      }
+
+      logInfo(`Permission request ${existingRequestId}: duplicate submission`)
+      return createSuccessResponse({
+        requestId: existingRequestId,
+        expiresAt: existingRequest.expiresAt,
+        expiresIn: existingRequest.expiresAt - Date.now(),
+        duplicate: true,
+        message: 'Permission request already exists'
+      })
+    }
 
     // Validate that the target address exists in the store
     const targetData = kvStore.get(targetSS58Address)
@@ -457,22 +775,35 @@ const processPermissionRequest = async (request, kvStore) => {
       return createErrorResponse('Target peer not found')
     }
 
+    if (expiresIn !== undefined && (!Number.isInteger(expiresIn) || expiresIn < MIN_PERMISSION_REQUEST_TTL || expiresIn > PERMISSION_REQUEST_TTL)) {
+      return createErrorResponse(`expiresIn must be between ${MIN_PERMISSION_REQUEST_TTL} and ${PERMISSION_REQUEST_TTL} ms`)
+    }
+
     // Store the permission request
     const requestId = crypto.randomUUID()
-    const expiresAt = Date.now() + (10 * 60 * 1000) // 10 minutes
+    const createdAt = Date.now()
+    const expiresAt = createdAt + (expiresIn ?? PERMISSION_REQUEST_TTL)
     permissionRequests.set(requestId, {
       targetSS58Address,
       requesterSS58Address,
       requesterPeerId,
       status: 'pending',
       expiresAt,
-      createdAt: Date.now()
+      createdAt,
+      idempotencyIndexKey
     })
+    if (idempotencyIndexKey) {
+      permissionRequestIdsByKey.set(idempotencyIndexKey, requestId)
+    }
+    relayMetrics.permissionRequests.created++
+    emitPermissionEvent('permission:requested', requestId, permissionRequests.get(requestId))
 
     logInfo(`Permission request ${requestId}: ${requesterSS58Address} wants to connect to ${targetSS58Address}`)
 
     return createSuccessResponse({
       requestId,
+      expiresAt,
+      expiresIn: expiresAt - createdAt,
       message: 'Permission request forwarded'
     })
 
@@ -486,13 +817,33 @@ const processPermissionRequest = async (request, kvStore) => {
     }
 
     if (Date.now() > permissionRequest.expiresAt) {
-      permissionRequests.delete(requestId)
+      expirePermissionRequest(requestId, permissionRequest)
       return createErrorResponse('Permission request has expired')
     }
 
+    if (permissionRequest.status === 'cancelled') {
+      return createErrorResponse('Permission request was cancelled')
+    }
+
+    // Repeating the same answer is a no-op; changing an answer is not allowed
+    if (permissionRequest.status !== 'pending') {
+      const repeatedStatus = accepted ? 'accepted' : 'rejected'
+      if (permissionRequest.status !== repeatedStatus) {
+        return createErrorResponse(`Permission request was already ${permissionRequest.status}`)
+      }
+      return createSuccessResponse({
+        message: `Permission request ${repeatedStatus}`,
+        accepted,
+        duplicate: true
+      })
+    }
+
     // Update the request status
     permissionRequest.status = accepted ? 'accepted' : 'rejected'
     permissionRequest.respondedAt = Date.now()
+    relayMetrics.permissionRequests[permissionRequest.status]++
+    observe(relayMetrics.permissionResponseSeconds, (permissionRequest.respondedAt - permissionRequest.createdAt) / 1000)
+    emitPermissionEvent(`permission:${permissionRequest.status}`, requestId, permissionRequest)
 
     logInfo(`Permission request ${requestId}: ${accepted ? 'accepted' : 'rejected'}`)
 
@@ -515,9 +866,15 @@ const processPermissionRequest = async (request, kvStore) => {
         createdAt: req.createdAt
       }))
 
+    // Let the target drop requests the requester has cancelled since the last check
+    const cancelledRequestIds = Array.from(permissionRequests.entries())
+      .filter(([id, req]) => req.targetSS58Address === targetSS58Address && req.status === 'cancelled')
+      .map(([id]) => id)
+
     return createSuccessResponse({
       pendingRequests,
-      count: pendingRequests.length
+      count: pendingRequests.length,
+      cancelledRequestIds
     })
 
   } else if (request.action === 'get_status') {
@@ -536,8 +893,45 @@ const processPermissionRequest = async (request, kvStore) => {
       respondedAt: permissionRequest.respondedAt
     })
 
+  } else if (request.action === 'cancel') {
+    // Requester is aborting a permission request it created
+    const { requestId } = request
+
+    const permissionRequest = permissionRequests.get(requestId)
+    if (!permissionRequest) {
+      return createErrorResponse('Permission request not found')
+    }
+
+    if (permissionRequest.requesterPeerId !== connection.remotePeer.toString()) {
+      return createErrorResponse('Only the requester may cancel a permission request')
+    }
+
+    // Cancelling twice is a no-op; an answered request can no longer be cancelled
+    if (permissionRequest.status === 'cancelled') {
+      return createSuccessResponse({
+        requestId,
+        message: 'Permission request cancelled',
+        duplicate: true
+      })
+    }
+    if (permissionRequest.status !== 'pending') {
+      return createErrorResponse(`Permission request was already ${permissionRequest.status}`)
+    }
+
+    permissionRequest.status = 'cancelled'
+    permissionRequest.respondedAt = Date.now()
+    relayMetrics.permissionRequests.cancelled++
+    emitPermissionEvent('permission:cancelled', requestId, permissionRequest)
+
+    logInfo(`Permission request ${requestId}: cancelled`)
+
+    return createSuccessResponse({
+      requestId,
+      message: 'Permission request cancelled'
+    })
+
   } else {
-    throw new Error('Invalid action. Must be "request", "respond", "check", or "get_status"')
+    throw new Error('Invalid action. Must be "request", "respond", "check", "get_status", or "cancel"')
   }
 }
 
@@ -549,19 +943,26 @@ const processConnectionChallengeRequest = async (request, kvStore) => {
   return createErrorResponse('Connection challenges are handled peer-to-peer')
 }
 
-const handlePermissionRequestStream = async (streamReader, streamWriter, kvStore) => {
+const handlePermissionRequestStream = async (streamReader, streamWriter, kvStore, connection, rateLimiter) => {
   while (true) {
     const data = await streamReader.read()
     if (data === null) {
       break // End of stream
     }
 
+    observe(relayMetrics.messageBytes, data.byteLength)
+    const limitResponse = rateLimiter.checkMessage(connection, data)
+    if (limitResponse) {
+      await writeResponse(streamWriter, limitResponse)
+      continue
+    }
+
     const message = toString(data.subarray())
     let response
 
     try {
       const request = JSON.parse(message)
-      response = await processPermissionRequest(request, kvStore)
+      response = await processPermissionRequest(request, kvStore, connection)
     } catch (error) {
       if (error instanceof SyntaxError) {
         logError(`Invalid JSON: ${message}`)
@@ -576,13 +977,20 @@ const handlePermissionRequestStream = async (streamReader, streamWriter, kvStore
   }
 }
 
-const handleConnectionChallengeStream = async (streamReader, streamWriter, kvStore) => {
+const handleConnectionChallengeStream = async (streamReader, streamWriter, kvStore, connection, rateLimiter) => {
   while (true) {
     const data = await streamReader.read()
     if (data === null) {
       break // End of stream
     }
 
+    observe(relayMetrics.messageBytes, data.byteLength)
+    const limitResponse = rateLimiter.checkMessage(connection, data)
+    if (limitResponse) {
+      await writeResponse(streamWriter, limitResponse)
+      continue
+    }
+
     const message = toString(data.subarray())
     let response
 
@@ -604,13 +1012,13 @@ const handleConnectionChallengeStream = async (streamReader, streamWriter, kvSto
 }
 
 // Connection Permission Protocol Handler
-const setupConnectionPermissionHandler = (server, kvStore) => {
+const setupConnectionPermissionHandler = (server, kvStore, rateLimiter) => {
   server.handle(CONNECTION_PERMISSION_PROTOCOL, async ({ stream, connection }) => {
     const streamReader = byteStream(stream)
     const streamWriter = byteStream(stream)
 
     try {
-      await handlePermissionRequestStream(streamReader, streamWriter, kvStore)
+      await handlePermissionRequestStream(streamReader, streamWriter, kvStore, connection, rateLimiter)
     } catch (error) {
       if (error.code !== STREAM_ABORT_ERROR) {
         logError(`Permission request stream error: ${error.message}`)
@@ -620,13 +1028,13 @@ const setupConnectionPermissionHandler = (server, kvStore) => {
 }
 
 // Connection Challenge Protocol Handler
-const setupConnectionChallengeHandler = (server, kvStore) => {
+const setupConnectionChallengeHandler = (server, kvStore, rateLimiter) => {
   server.handle(CONNECTION_CHALLENGE_PROTOCOL, async ({ stream, connection }) => {
     const streamReader = byteStream(stream)
     const streamWriter = byteStream(stream)
 
     try {
-      await handleConnectionChallengeStream(streamReader, streamWriter, kvStore)
+      await handleConnectionChallengeStream(streamReader, streamWriter, kvStore, connection, rateLimiter)
     } catch (error) {
       if (error.code !== STREAM_ABORT_ERROR) {
         logError(`Connection challenge stream error: ${error.message}`)
@@ -636,12 +1044,324 @@ const setupConnectionChallengeHandler = (server, kvStore) => {
 }
 
 // Main Handler Setup Function
-export function setupRelayHandlers(server, kvStore) {
-  setupKvStorageHandler(server, kvStore)
-  setupKvQueryHandler(server, kvStore)
-  setupProofOfPossessionHandler(server, kvStore)
-  setupConnectionChallengeHandler(server, kvStore)
-  setupConnectionPermissionHandler(server, kvStore)
+export function setupRelayHandlers(server, kvStore, options = {}) {
+  const {
+    rateLimiter = createRateLimiter(options.rateLimits),
+    requireBytesWrapping = false // Only accept proofs signed over <Bytes>-wrapped challenges
+  } = options
+
+  // Forget token buckets of closed connections
+  server.addEventListener('connection:close', (event) => {
+    rateLimiter.releaseConnection(event.detail.id)
+  })
+
+  setupKvStorageHandler(server, kvStore, rateLimiter)
+  setupKvQueryHandler(server, kvStore, rateLimiter)
+  setupProofOfPossessionHandler(server, kvStore, rateLimiter, requireBytesWrapping)
+  setupConnectionChallengeHandler(server, kvStore, rateLimiter)
+  setupConnectionPermissionHandler(server, kvStore, rateLimiter)
+
+  startCleanupTimer(server)
+
+  return { rateLimiter }
+}
+
+// Webhook Notifications
+const WEBHOOK_EVENTS = [
+  'address:registered',
+  'permission:requested',
+  'permission:accepted',
+  'permission:rejected',
+  'permission:cancelled',
+  'permission:expired'
+]
+const WEBHOOK_TIMEOUT = 5000 // A slow receiver must not hold deliveries open indefinitely
+
+// POST every lifecycle event as JSON to the configured URLs. The body is signed with HMAC-SHA256 and
+// sent in the X-Relay-Signature header so receivers can tell the relay's events from forged ones
+export const startWebhookNotifier = ({ urls = [], secret, timeout = WEBHOOK_TIMEOUT } = {}) => {
+  if (!secret) {
+    throw new Error('Webhooks require a secret to sign events with')
+  }
+
+  const deliver = async (event, data) => {
+    const body = JSON.stringify({ event, timestamp: Date.now(), data })
+    const headers = {
+      'Content-Type': 'application/json',
+      'X-Relay-Signature': `sha256=${createHmac('sha256', secret).update(body).digest('hex')}`
+    }
+
+    await Promise.all(urls.map(async (url) => {
+      try {
+        const response = await fetch(url, { method: 'POST', headers, body, signal: AbortSignal.timeout(timeout) })
+        if (!response.ok) {
+          logError(`Webhook ${url} rejected ${event}: HTTP ${response.status}`)
+        }
+      } catch (error) {
+        logError(`Webhook ${url} failed for ${event}: ${error.message}`)
+      }
+    }))
+  }
+
+  const listeners = WEBHOOK_EVENTS.map((event) => {
+    const listener = (data) => deliver(event, data)
+    relayEvents.on(event, listener)
+    return [event, listener]
+  })
+
+  logInfo(`Webhooks: notifying ${urls.length} URL(s)`)
+
+  return {
+    stop: () => listeners.forEach(([event, listener]) => relayEvents.off(event, listener))
+  }
+}
+
+// Push Notifications
+const PUSH_GATEWAY_TIMEOUT = 5000
+// Digest of the fields a mobile signer should confirm before approving the request
+const permissionRequestDigest = ({ requestId, requesterSS58Address, targetSS58Address }) =>
+  createHash('sha256').update(JSON.stringify([requestId, requesterSS58Address, targetSS58Address])).digest('hex')
+
+// Deliver push notifications through a generic push gateway, which maps the recipient SS58 address
+// to the device tokens it holds and forwards the notification to FCM or APNs
+export const createPushGatewaySender = ({ url, token, timeout = PUSH_GATEWAY_TIMEOUT }) => async (notification) => {
+  const headers = { 'Content-Type': 'application/json' }
+  if (token) {
+    headers.Authorization = `Bearer ${token}`
+  }
+
+  const response = await fetch(url, {
+    method: 'POST',
+    headers,
+    body: JSON.stringify(notification),
+    signal: AbortSignal.timeout(timeout)
+  })
+  if (!response.ok) {
+    throw new Error(`HTTP ${response.status}`)
+  }
+}
+
+// Notify the target of every new permission request so it can approve without a persistent connection.
+// `send` receives { recipient, requestId, requesterSS58Address, digest } and may deliver it any way it likes
+export const startPushNotifier = ({ send }) => {
+  const listener = async ({ requestId, requesterSS58Address, targetSS58Address }) => {
+    try {
+      await send({
+        recipient: targetSS58Address,
+        requestId,
+        requesterSS58Address,
+        digest: permissionRequestDigest({ requestId, requesterSS58Address, targetSS58Address })
+      })
+    } catch (error) {
+      logError(`Push notification for ${requestId} failed: ${error.message}`)
+    }
+  }
+  relayEvents.on('permission:requested', listener)
+
+  return {
+    stop: () => relayEvents.off('permission:requested', listener)
+  }
+}
+
+// Audit Log
+const AUDIT_LOG_EVENTS = [...WEBHOOK_EVENTS, 'registration:failed']
+const AUDIT_LOG_GENESIS_HASH = '0'.repeat(64)
+const AUDIT_LOG_MAX_ENTRIES = 10000 // Newest entries kept in memory; the file keeps the full chain
+const AUDIT_LOG_MAX_FIELD_LENGTH = 128 // Event fields such as addresses come from clients
+const AUDIT_LOG_TAIL_BYTES = 64 * 1024 // Enough to hold the last entry of the file
+
+const hashAuditEntry = ({ index, timestamp, event, data, previousHash }) =>
+  createHash('sha256').update(JSON.stringify({ index, timestamp, event, data, previousHash })).digest('hex')
+
+const truncateAuditData = (data) => Object.fromEntries(Object.entries(data ?? {}).map(([key, value]) => [
+  key,
+  typeof value === 'string' && value.length > AUDIT_LOG_MAX_FIELD_LENGTH ? `${value.slice(0, AUDIT_LOG_MAX_FIELD_LENGTH)}...` : value
+]))
+
+// Check that every entry hashes correctly and commits to its predecessor. A partial log is checked
+// from the entry before its first one, given as `startIndex` and `previousHash`. Comparing the
+// returned head with a previously recorded head also reveals truncation
+export const verifyAuditLog = (entries, { startIndex = 0, previousHash = AUDIT_LOG_GENESIS_HASH } = {}) => {
+  for (const [position, entry] of entries.entries()) {
+    if (entry.index !== startIndex + position || entry.previousHash !== previousHash || entry.hash !== hashAuditEntry(entry)) {
+      return { valid: false, brokenAt: startIndex + position }
+    }
+    previousHash = entry.hash
+  }
+
+  return { valid: true, head: previousHash, length: startIndex + entries.length }
+}
+
+// Read the last entry of an audit log file so a restarted relay continues its chain
+const readLastAuditEntry = (filePath) => {
+  let fd
+  try {
+    fd = openSync(filePath, 'r')
+  } catch (error) {
+    if (error.code === 'ENOENT') return null
+    throw error
+  }
+
+  try {
+    const { size } = fstatSync(fd)
+    const length = Math.min(size, AUDIT_LOG_TAIL_BYTES)
+    const tail = Buffer.alloc(length)
+    readSync(fd, tail, 0, length, size - length)
+    const lastLine = tail.toString('utf8').trimEnd().split('\n').pop()
+    if (!lastLine) return null
+
+    const lastEntry = JSON.parse(lastLine)
+    if (!Number.isInteger(lastEntry?.index) || typeof lastEntry.hash !== 'string') {
+      throw new Error('last entry has no index or hash')
+    }
+    return lastEntry
+  } finally {
+    closeSync(fd)
+  }
+}
+
+// Record security-relevant relay events in an append-only, hash-chained log,
+// optionally mirrored to a JSON-lines file. Throws if the file's last entry cannot be read, because
+// appending a new chain to it would hide whatever was lost or tampered with
+export const startAuditLog = ({ filePath, maxEntries = AUDIT_LOG_MAX_ENTRIES } = {}) => {
+  const entries = []
+  let nextIndex = 0
+  let head = AUDIT_LOG_GENESIS_HASH
+  // The entry before the oldest one kept in memory, so the in-memory tail can still be verified
+  let tailStart = { startIndex: 0, previousHash: AUDIT_LOG_GENESIS_HASH }
+
+  if (filePath) {
+    let lastEntry
+    try {
+      lastEntry = readLastAuditEntry(filePath)
+    } catch (error) {
+      throw new Error(`Audit log ${filePath} could not be read (${error.message}); move it aside after inspecting it to start a new chain`)
+    }
+
+    if (lastEntry) {
+      nextIndex = lastEntry.index + 1
+      head = lastEntry.hash
+      tailStart = { startIndex: nextIndex, previousHash: head }
+      logInfo(`Audit log: continuing ${filePath} from entry ${nextIndex}`)
+    }
+  }
+
+  const append = (event, data) => {
+    const entry = {
+      index: nextIndex++,
+      timestamp: Date.now(),
+      event,
+      data: truncateAuditData(data),
+      previousHash: head
+    }
+    entry.hash = hashAuditEntry(entry)
+    head = entry.hash
+    entries.push(entry)
+
+    if (entries.length > maxEntries) {
+      const dropped = entries.shift()
+      tailStart = { startIndex: dropped.index + 1, previousHash: dropped.hash }
+    }
+
+    if (filePath) {
+      try {
+        appendFileSync(filePath, JSON.stringify(entry) + '\n')
+      } catch (error) {
+        logError(`Audit log write failed: ${error.message}`)
+      }
+    }
+  }
+
+  const listeners = AUDIT_LOG_EVENTS.map((event) => {
+    const listener = (data) => append(event, data)
+    relayEvents.on(event, listener)
+    return [event, listener]
+  })
+
+  return {
+    export: () => ({ head, ...tailStart, entries: [...entries] }),
+    verify: () => verifyAuditLog(entries, tailStart),
+    stop: () => listeners.forEach(([event, listener]) => relayEvents.off(event, listener))
+  }
+}
+
+// Metrics Rendering (Prometheus text exposition format)
+const renderCounter = (name, help, label, values) => [
+  `# HELP ${name} ${help}`,
+  `# TYPE ${name} counter`,
+  ...Object.entries(values).map(([labelValue, value]) => `${name}{${label}="${labelValue}"} ${value}`)
+]
+
+const renderGauge = (name, help, value) => [
+  `# HELP ${name} ${help}`,
+  `# TYPE ${name} gauge`,
+  `${name} ${value}`
+]
+
+const renderHistogram = (name, help, histogram) => [
+  `# HELP ${name} ${help}`,
+  `# TYPE ${name} histogram`,
+  ...histogram.buckets.map((bucket, index) => `${name}_bucket{le="${bucket}"} ${histogram.counts[index]}`),
+  `${name}_bucket{le="+Inf"} ${histogram.count}`,
+  `${name}_sum ${histogram.sum}`,
+  `${name}_count ${histogram.count}`
+]
+
+export const renderMetrics = (kvStore, rateLimiter) => {
+  const pendingPermissionRequests = Array.from(permissionRequests.values())
+    .filter(req => req.status === 'pending').length
+
+  return [
+    ...renderCounter('relay_registrations_total', 'Proof of possession registrations by result', 'result', relayMetrics.registrations),
+    ...renderCounter('relay_permission_requests_total', 'Connection permission requests by outcome', 'status', relayMetrics.permissionRequests),
+    ...renderCounter('relay_rejected_messages_total', 'Messages rejected by the rate limiter by reason', 'reason', {
+      rate_limited: rateLimiter.metrics.rateLimited,
+      oversized: rateLimiter.metrics.oversized,
+      registration_limit: rateLimiter.metrics.registrationsRejected
+    }),
+    ...renderGauge('relay_registered_addresses', 'Addresses currently stored in the relay', kvStore.size),
+    ...renderGauge('relay_pending_permission_requests', 'Connection permission requests awaiting a response', pendingPermissionRequests),
+    ...renderHistogram('relay_message_bytes', 'Size of protocol messages received by the relay', relayMetrics.messageBytes),
+    ...renderHistogram('relay_permission_response_seconds', 'Time from permission request to accept or reject', relayMetrics.permissionResponseSeconds)
+  ].join('\n') + '\n'
+}
+
+// Compare digests so the check takes the same time however much of the token matches
+const isAuthorized = (req, token) => {
+  const digest = (value) => createHash('sha256').update(value).digest()
+  return timingSafeEqual(digest(req.headers.authorization ?? ''), digest(`Bearer ${token}`))
+}
+
+// Metrics HTTP Server. It also serves the audit log export when one is running and an
+// auditLogToken is set; the export reveals who asked to connect to whom, so it needs the token
+export const startMetricsServer = ({ port = DEFAULT_METRICS_PORT, host = DEFAULT_METRICS_HOST, kvStore, rateLimiter, auditLog, auditLogToken }) => {
+  const metricsServer = http.createServer((req, res) => {
+    if (req.method === 'GET' && req.url === '/metrics') {
+      res.writeHead(200, { 'Content-Type': 'text/plain; version=0.0.4' })
+      res.end(renderMetrics(kvStore, rateLimiter))
+    } else if (req.method === 'GET' && req.url === '/audit-log' && auditLog && auditLogToken) {
+      if (!isAuthorized(req, auditLogToken)) {
+        res.writeHead(401, { 'WWW-Authenticate': 'Bearer' })
+        res.end()
+        return
+      }
+      res.writeHead(200, { 'Content-Type': 'application/json' })
+      res.end(JSON.stringify(auditLog.export()))
+    } else {
+      res.writeHead(404)
+      res.end()
+    }
+  })
+
+  // A busy port disables metrics instead of taking the relay down
+  metricsServer.on('error', (error) => {
+    logError(`Metrics server failed on ${host}:${port}: ${error.message}`)
+  })
+  metricsServer.listen(port, host, () => {
+    logInfo(`Metrics available on: http://${host}:${port}/metrics`)
+  })
+
+  return metricsServer
 }
 
 // Server Startup and Logging
@@ -654,12 +1374,43 @@ const logServerInfo = (server) => {
 
 // Standalone Server Startup
 const startStandaloneServer = async () => {
+  // Opened first so that an unreadable audit log stops the relay before it accepts connections
+  const auditLog = startAuditLog({ filePath: process.env.AUDIT_LOG_FILE })
   const { server, kvStore: serverKvStore } = await createRelayServer()
-  setupRelayHandlers(server, serverKvStore)
+  const { rateLimiter } = setupRelayHandlers(server, serverKvStore, {
+    requireBytesWrapping: process.env.REQUIRE_BYTES_WRAPPING === 'true'
+  })
   logServerInfo(server)
+  if (process.env.METRICS_PORT) {
+    startMetricsServer({
+      port: process.env.METRICS_PORT,
+      host: process.env.METRICS_HOST,
+      kvStore: serverKvStore,
+      rateLimiter,
+      auditLog,
+      auditLogToken: process.env.AUDIT_LOG_TOKEN
+    })
+  }
+
+  const webhookUrls = (process.env.WEBHOOK_URLS || '').split(',').map(url => url.trim()).filter(Boolean)
+  if (webhookUrls.length > 0 && !process.env.WEBHOOK_SECRET) {
+    logError('WEBHOOK_URLS is set without WEBHOOK_SECRET; webhooks are disabled because their events could not be authenticated')
+  } else if (webhookUrls.length > 0) {
+    startWebhookNotifier({ urls: webhookUrls, secret: process.env.WEBHOOK_SECRET })
+  }
+
+  if (process.env.PUSH_GATEWAY_URL) {
+    startPushNotifier({
+      send: createPushGatewaySender({ url: process.env.PUSH_GATEWAY_URL, token: process.env.PUSH_GATEWAY_TOKEN })
+    })
+    logInfo(`Push notifications: sending to ${process.env.PUSH_GATEWAY_URL}`)
+  }
 }
 
 // For standalone server usage - only run if this file is executed directly
 if (import.meta.url === `file://${process.argv[1]}`) {
-  startStandaloneServer()
+  startStandaloneServer().catch((error) => {
+    logError(`Relay failed to start: ${error.message}`)
+    process.exit(1)
+  })
 }
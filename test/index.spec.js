@@ -1,8 +1,28 @@
 // Browser Integration Tests for Decentralized Threshold Signing Service
 
 import { test, expect } from '@playwright/test'
-import { createRelayServer, setupRelayHandlers } from '../relay.js'
+import {
+  cleanupExpiredEntries,
+  createPushGatewaySender,
+  createRelayServer,
+  createRateLimiter,
+  processKvQuery,
+  processKvStorageRequest,
+  processPermissionRequest,
+  setupRelayHandlers,
+  relayEvents,
+  renderMetrics,
+  startAuditLog,
+  startPushNotifier,
+  startWebhookNotifier,
+  verifyAuditLog
+} from '../relay.js'
 import { spawn } from 'child_process'
+import { createHash, createHmac } from 'crypto'
+import { appendFileSync, mkdtempSync, readFileSync } from 'fs'
+import { createServer } from 'http'
+import { tmpdir } from 'os'
+import { join } from 'path'
 
 // DOM Selectors
 const SELECTORS = {
@@ -24,7 +44,9 @@ const TEST_CONFIG = {
   testSS58AddressA: '5CXkZyy4S5b3w16wvKA2hUwzp5q2y7UtRPkXnW97QGvDN8Jw',
   testSS58AddressB: '5Gma8SNsn6rkQf9reAWFQ9WKq8bwwHtSzwMYtLTdhYsGPKiy',
   testSecretKeyA: '0x473a77675b8e77d90c1b6dc2dbe6ac533b0853790ea8bcadf0ee8b5da4cfbbce',
-  testSecretKeyB: '0xdb9ddbb3d6671c4de8248a4fba95f3d873dc21a0434b52951bb33730c1ac93d7'
+  testSecretKeyB: '0xdb9ddbb3d6671c4de8248a4fba95f3d873dc21a0434b52951bb33730c1ac93d7',
+  testSS58AddressAlice: '5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY',
+  testSecretUriAlice: '//Alice'
 }
 
 // Test Timeouts
@@ -44,7 +66,7 @@ let viteServerB
 const startViteServer = (port) => {
   return new Promise((resolve, reject) => {
     const server = spawn('npm', ['start'], {
-      env: { ...process.env, VITE_PORT: port.toString() },
+      env: { ...process.env, VITE_PORT: port.toString(), VITE_TEST_HOOKS: 'true' },
       stdio: 'pipe'
     })
 
@@ -221,6 +243,7 @@ test.describe('browser to browser example:', () => {
     await expect(pageBOutput).toContainText('Initiating connection proof of possession...')
     await expect(pageBOutput).toContainText('Received challenge:')
     await expect(pageBOutput).toContainText('Our signature verified!')
+    await expect(pageBOutput).toContainText('Peer signature verified!')
     await expect(pageBOutput).toContainText('Received mutual challenge:')
     await expect(pageBOutput).toContainText('Mutual connection proof of possession completed!')
 
@@ -284,6 +307,515 @@ test.describe('browser to browser example:', () => {
     await contextA.close()
     await contextB.close()
   })
+
+  // Message Resend Test
+  test('should resend a lost message over the open stream after a later message was delivered', async ({ browser }) => {
+    test.setTimeout(TIMEOUTS.mainTest)
+
+    const contextA = await browser.newContext()
+    const contextB = await browser.newContext()
+
+    const pageA = await contextA.newPage()
+    const pageB = await contextB.newPage()
+
+    await pageA.goto(testUrlA)
+    await pageB.goto(testUrlB)
+
+    await waitForRelayConnection(pageA)
+    await waitForRelayConnection(pageB)
+
+    await storeSS58Address(pageA, TEST_CONFIG.testSS58AddressA, TEST_CONFIG.testSecretKeyA)
+    await storeSS58Address(pageB, TEST_CONFIG.testSS58AddressB, TEST_CONFIG.testSecretKeyB)
+
+    await connectViaSS58AddressWithPermission(pageB, pageA, TEST_CONFIG.testSS58AddressA)
+    await sendMessage(pageA, pageB, 'hello B from A before the drop')
+
+    const pageAOutput = pageA.locator(SELECTORS.output)
+    const pageBOutput = pageB.locator(SELECTORS.output)
+    const countStreamOpenings = async () => (await pageAOutput.textContent()).split('Opening chat stream').length - 1
+    const streamOpeningsBefore = await countStreamOpenings()
+
+    // Lose the first write on the open stream, then send a second message before the first one is resent
+    await pageA.evaluate(() => window.loseChatWrites(1))
+    await pageA.fill(SELECTORS.messageInput, 'dropped then resent')
+    await pageA.click(SELECTORS.sendButton)
+    await pageA.fill(SELECTORS.messageInput, 'sent after the drop')
+    await pageA.click(SELECTORS.sendButton)
+
+    await expect(pageBOutput).toContainText(`Received: 'sent after the drop'`)
+    await expect(pageAOutput).toContainText('Resending message')
+    await expect(pageBOutput).toContainText(`Received: 'dropped then resent'`)
+
+    // The resend went over the stream that was already open
+    expect(await countStreamOpenings()).toBe(streamOpeningsBefore)
+
+    await contextA.close()
+    await contextB.close()
+  })
+
+  // Envelope Rejection Test
+  test('should reject duplicated, foreign-session and tampered envelopes', async ({ browser }) => {
+    test.setTimeout(TIMEOUTS.mainTest)
+
+    const contextA = await browser.newContext()
+    const contextB = await browser.newContext()
+
+    const pageA = await contextA.newPage()
+    const pageB = await contextB.newPage()
+
+    await pageA.goto(testUrlA)
+    await pageB.goto(testUrlB)
+
+    await waitForRelayConnection(pageA)
+    await waitForRelayConnection(pageB)
+
+    await storeSS58Address(pageA, TEST_CONFIG.testSS58AddressA, TEST_CONFIG.testSecretKeyA)
+    await storeSS58Address(pageB, TEST_CONFIG.testSS58AddressB, TEST_CONFIG.testSecretKeyB)
+
+    await connectViaSS58AddressWithPermission(pageB, pageA, TEST_CONFIG.testSS58AddressA)
+    await sendMessage(pageA, pageB, 'hello B from A before the forgeries')
+
+    const pageBOutput = pageB.locator(SELECTORS.output)
+
+    // Duplicated: the same signed sequence number is delivered once
+    const sequence = await pageA.evaluate(() => window.sendTestEnvelope({ payload: 'sent twice' }))
+    await expect(pageBOutput).toContainText(`Received: 'sent twice'`)
+    await pageA.evaluate((sequence) => window.sendTestEnvelope({ payload: 'sent twice', fields: { sequence } }), sequence)
+    await expect(pageBOutput).toContainText(`Ignored duplicate message ${sequence}`)
+    expect((await pageBOutput.textContent()).split(`Received: 'sent twice'`).length - 1).toBe(1)
+
+    // Foreign session: correctly signed, but for another session than the connected peer's
+    await pageA.evaluate(() => window.sendTestEnvelope({ payload: 'from another session', fields: { sessionId: 'another-session' } }))
+    await expect(pageBOutput).toContainText('Rejected message: Envelope belongs to session another-session')
+
+    // Tampered: the payload is changed after signing
+    await pageA.evaluate(() => window.sendTestEnvelope({ payload: 'signed payload', tampered: { payload: 'tampered payload' } }))
+    await expect(pageBOutput).toContainText('Rejected message: Invalid envelope signature')
+
+    await expect(pageBOutput).not.toContainText('from another session')
+    await expect(pageBOutput).not.toContainText('tampered payload')
+
+    await contextA.close()
+    await contextB.close()
+  })
+
+  // Secret URI Registration Test
+  test('should register an address derived from a secret URI', async ({ browser }) => {
+    test.setTimeout(TIMEOUTS.mainTest)
+
+    const context = await browser.newContext()
+    const page = await context.newPage()
+
+    await page.goto(testUrlA)
+    await waitForRelayConnection(page)
+
+    // Register the development account derived from //Alice
+    await storeSS58Address(page, TEST_CONFIG.testSS58AddressAlice, TEST_CONFIG.testSecretUriAlice)
+    await expect(page.locator(SELECTORS.output)).toContainText('Valid secret URI')
+
+    // A secret URI for a different account must be refused before contacting the relay
+    await page.fill(SELECTORS.secretKeyInput, '//Bob')
+    await page.click(SELECTORS.storeAddressButton)
+    await expect(page.locator(SELECTORS.output)).toContainText('Secret key does not match the SS58 address')
+
+    await context.close()
+  })
+})
+
+// Fake libp2p connection for calling relay handlers directly
+const fakeConnection = (peerId, host = '10.0.0.1') => ({
+  id: `${peerId}-connection`,
+  remotePeer: { toString: () => peerId },
+  remoteAddr: { toOptions: () => ({ host }), toString: () => host }
+})
+
+// Local HTTP server that answers every request with `status` and resolves `nextRequest` with it
+const startRecordingServer = async (status = 200) => {
+  let onRequest
+  const requests = []
+  const server = createServer((req, res) => {
+    let body = ''
+    req.on('data', (chunk) => { body += chunk })
+    req.on('end', () => {
+      requests.push({ headers: req.headers, body })
+      onRequest?.()
+      res.writeHead(status).end()
+    })
+  })
+  await new Promise(resolve => server.listen(0, '127.0.0.1', resolve))
+
+  return {
+    url: `http://127.0.0.1:${server.address().port}/`,
+    requests,
+    nextRequest: (count = requests.length + 1) => new Promise((resolve) => {
+      onRequest = () => requests.length >= count && resolve(requests[count - 1])
+      onRequest()
+    }),
+    close: () => new Promise(resolve => server.close(resolve))
+  }
+}
+
+// Test Suite: Relay Key-Value Store
+test.describe('relay key-value store:', () => {
+  test('should refuse unproven address registrations and deletes by other peers', async () => {
+    const kvStore = new Map()
+    const rateLimiter = createRateLimiter()
+    const owner = fakeConnection('peer-owner')
+    const stranger = fakeConnection('peer-stranger')
+
+    await expect(processKvStorageRequest({ key: TEST_CONFIG.testSS58AddressA, value: '/ip4/6.6.6.6' }, kvStore, stranger, rateLimiter))
+      .rejects.toThrow('SS58 addresses can only be registered with proof of possession')
+
+    await processKvStorageRequest({ key: 'config', value: 'v1' }, kvStore, owner, rateLimiter)
+    await expect(processKvStorageRequest({ key: 'config', value: 'v2' }, kvStore, stranger, rateLimiter))
+      .rejects.toThrow('Key was stored by another peer')
+    expect(() => processKvQuery({ action: 'delete', key: 'config' }, kvStore, stranger, rateLimiter))
+      .toThrow('Only the peer that stored a key may delete it')
+
+    expect(processKvQuery({ action: 'get', key: 'config' }, kvStore, stranger, rateLimiter)).toMatchObject({ value: 'v1', found: true })
+    expect(processKvQuery({ action: 'delete', key: 'config' }, kvStore, owner, rateLimiter)).toMatchObject({ deleted: true })
+  })
+})
+
+// Test Suite: Relay Connection Permissions
+test.describe('relay connection permissions:', () => {
+  const requester = fakeConnection('peer-requester')
+  const target = fakeConnection('peer-target')
+  const kvStore = new Map([[TEST_CONFIG.testSS58AddressB, JSON.stringify({ ownerPeerId: 'peer-target' })]])
+
+  const createRequest = async (fields = {}) => processPermissionRequest({
+    action: 'request',
+    targetSS58Address: TEST_CONFIG.testSS58AddressB,
+    requesterSS58Address: TEST_CONFIG.testSS58AddressA,
+    requesterPeerId: 'peer-requester',
+    ...fields
+  }, kvStore, requester)
+
+  test('should only let the requester cancel a pending request and tell the target', async () => {
+    const { requestId } = await createRequest()
+    const check = () => processPermissionRequest({ action: 'check', targetSS58Address: TEST_CONFIG.testSS58AddressB }, kvStore, target)
+    expect((await check()).pendingRequests.map(request => request.requestId)).toContain(requestId)
+
+    expect(await processPermissionRequest({ action: 'cancel', requestId }, kvStore, target))
+      .toMatchObject({ success: false, error: 'Only the requester may cancel a permission request' })
+    expect(await processPermissionRequest({ action: 'cancel', requestId }, kvStore, requester)).toMatchObject({ success: true })
+    expect(await processPermissionRequest({ action: 'cancel', requestId }, kvStore, requester)).toMatchObject({ success: true, duplicate: true })
+
+    const afterCancel = await check()
+    expect(afterCancel.pendingRequests.map(request => request.requestId)).not.toContain(requestId)
+    expect(afterCancel.cancelledRequestIds).toContain(requestId)
+    expect(await processPermissionRequest({ action: 'respond', requestId, accepted: true }, kvStore, target))
+      .toMatchObject({ success: false, error: 'Permission request was cancelled' })
+  })
+
+  test('should refuse to cancel an answered request', async () => {
+    const { requestId } = await createRequest()
+    expect(await processPermissionRequest({ action: 'respond', requestId, accepted: true }, kvStore, target)).toMatchObject({ success: true })
+
+    expect(await processPermissionRequest({ action: 'cancel', requestId }, kvStore, requester))
+      .toMatchObject({ success: false, error: 'Permission request was already accepted' })
+    expect(await processPermissionRequest({ action: 'get_status', requestId }, kvStore, requester)).toMatchObject({ status: 'accepted' })
+  })
+
+  // Runs `action` with the relay's clock just past `expiresAt`
+  const afterExpiry = async (expiresAt, action) => {
+    const now = Date.now
+    Date.now = () => expiresAt + 1
+    try {
+      return await action()
+    } finally {
+      Date.now = now
+    }
+  }
+
+  test('should return the same request for a retried idempotency key until it expires', async () => {
+    const first = await createRequest({ idempotencyKey: 'retry-key', expiresIn: 60_000 })
+    expect(first).toMatchObject({ success: true, expiresIn: 60_000 })
+
+    const retry = await createRequest({ idempotencyKey: 'retry-key', expiresIn: 60_000 })
+    expect(retry).toMatchObject({ success: true, duplicate: true, requestId: first.requestId, expiresAt: first.expiresAt })
+    expect(retry.expiresIn).toBeLessThanOrEqual(60_000)
+
+    expect(await createRequest({ idempotencyKey: 'retry-key', targetSS58Address: TEST_CONFIG.testSS58AddressAlice }))
+      .toMatchObject({ success: false, error: 'Idempotency key was already used for a different target' })
+
+    // Another peer using the same key gets its own request
+    const other = await processPermissionRequest({
+      action: 'request',
+      targetSS58Address: TEST_CONFIG.testSS58AddressB,
+      requesterSS58Address: TEST_CONFIG.testSS58AddressAlice,
+      requesterPeerId: 'peer-other',
+      idempotencyKey: 'retry-key'
+    }, kvStore, fakeConnection('peer-other'))
+    expect(other.requestId).not.toBe(first.requestId)
+
+    const replacement = await afterExpiry(first.expiresAt, () => createRequest({ idempotencyKey: 'retry-key' }))
+    expect(replacement).toMatchObject({ success: true })
+    expect(replacement.duplicate).toBeUndefined()
+    expect(replacement.requestId).not.toBe(first.requestId)
+    expect(await processPermissionRequest({ action: 'get_status', requestId: first.requestId }, kvStore, requester))
+      .toMatchObject({ success: false, error: 'Permission request not found' })
+  })
+
+  test('should reject out-of-range lifetimes and answers after expiry', async () => {
+    for (const expiresIn of [1000, 10 * 60 * 1000 + 1, 45_000.5, '60000']) {
+      expect(await createRequest({ expiresIn })).toMatchObject({ success: false, error: 'expiresIn must be between 30000 and 600000 ms' })
+    }
+
+    const { requestId, expiresAt } = await createRequest({ expiresIn: 30_000 })
+    expect(await afterExpiry(expiresAt, () => processPermissionRequest({ action: 'respond', requestId, accepted: true }, kvStore, target)))
+      .toMatchObject({ success: false, error: 'Permission request has expired' })
+    expect(await processPermissionRequest({ action: 'get_status', requestId }, kvStore, requester))
+      .toMatchObject({ success: false, error: 'Permission request not found' })
+  })
+
+  test('should sweep expired requests and report unanswered ones', async () => {
+    const { requestId, expiresAt } = await createRequest()
+    const expired = []
+    const onExpired = (event) => expired.push(event.requestId)
+    relayEvents.on('permission:expired', onExpired)
+
+    cleanupExpiredEntries(expiresAt - 1)
+    expect(await processPermissionRequest({ action: 'get_status', requestId }, kvStore, requester)).toMatchObject({ status: 'pending' })
+
+    expect(cleanupExpiredEntries(expiresAt + 1).removedRequests).toBeGreaterThanOrEqual(1)
+    relayEvents.off('permission:expired', onExpired)
+
+    expect(expired).toContain(requestId)
+    expect(await processPermissionRequest({ action: 'get_status', requestId }, kvStore, requester))
+      .toMatchObject({ success: false, error: 'Permission request not found' })
+  })
+})
+
+// Test Suite: Relay Metrics
+test.describe('relay metrics:', () => {
+  // Sample name with labels -> value, for every non-comment line
+  const parseMetrics = (text) => new Map(text.trim().split('\n')
+    .filter(line => !line.startsWith('#'))
+    .map(line => [line.slice(0, line.lastIndexOf(' ')), Number(line.slice(line.lastIndexOf(' ') + 1))]))
+
+  test('should render labelled counters, gauges and cumulative histograms', async () => {
+    const kvStore = new Map([[TEST_CONFIG.testSS58AddressB, JSON.stringify({ ownerPeerId: 'peer-target' })]])
+    const rateLimiter = createRateLimiter({ maxMessageSize: 8 })
+    rateLimiter.checkMessage(fakeConnection('peer-metrics'), new Uint8Array(9))
+    const before = parseMetrics(renderMetrics(kvStore, rateLimiter))
+
+    // A request answered after 10 seconds lands in every bucket from 15 seconds up
+    const { requestId, expiresAt, expiresIn } = await processPermissionRequest({
+      action: 'request',
+      targetSS58Address: TEST_CONFIG.testSS58AddressB,
+      requesterSS58Address: TEST_CONFIG.testSS58AddressA,
+      requesterPeerId: 'peer-metrics'
+    }, kvStore, fakeConnection('peer-metrics'))
+    const now = Date.now
+    Date.now = () => expiresAt - expiresIn + 10_000
+    try {
+      await processPermissionRequest({ action: 'respond', requestId, accepted: true }, kvStore, fakeConnection('peer-target'))
+    } finally {
+      Date.now = now
+    }
+
+    const text = renderMetrics(kvStore, rateLimiter)
+    const after = parseMetrics(text)
+    const increase = (sample) => after.get(sample) - before.get(sample)
+
+    expect(text).toContain('# TYPE relay_permission_requests_total counter')
+    expect(text).toContain('# TYPE relay_permission_response_seconds histogram')
+    expect(increase('relay_permission_requests_total{status="created"}')).toBe(1)
+    expect(increase('relay_permission_requests_total{status="accepted"}')).toBe(1)
+    expect(after.get('relay_rejected_messages_total{reason="oversized"}')).toBe(1)
+    expect(after.get('relay_rejected_messages_total{reason="rate_limited"}')).toBe(0)
+    expect(after.get('relay_registered_addresses')).toBe(1)
+
+    const bucketBounds = ['1', '5', '15', '60', '300', '600', '+Inf']
+    const bucket = (le) => `relay_permission_response_seconds_bucket{le="${le}"}`
+    expect(bucketBounds.map(le => increase(bucket(le)))).toEqual([0, 0, 1, 1, 1, 1, 1])
+    expect(increase('relay_permission_response_seconds_count')).toBe(1)
+    expect(increase('relay_permission_response_seconds_sum')).toBeCloseTo(10)
+
+    // Buckets are cumulative and +Inf counts every observation
+    const counts = bucketBounds.map(le => after.get(bucket(le)))
+    expect(counts).toEqual([...counts].sort((a, b) => a - b))
+    expect(after.get(bucket('+Inf'))).toBe(after.get('relay_permission_response_seconds_count'))
+  })
+})
+
+// Test Suite: Relay Rate Limiter
+test.describe('relay rate limiter:', () => {
+  test('should reject oversized and bursty messages', () => {
+    const rateLimiter = createRateLimiter({ burstSize: 2, messagesPerSecond: 1, maxMessageSize: 8 })
+    const connection = fakeConnection('conn-1', '10.0.0.1')
+
+    expect(rateLimiter.checkMessage(connection, new Uint8Array(9))).toMatchObject({ success: false, status: 413 })
+    expect(rateLimiter.checkMessage(connection, new Uint8Array(1))).toBeNull()
+    expect(rateLimiter.checkMessage(connection, new Uint8Array(1))).toBeNull()
+    expect(rateLimiter.checkMessage(connection, new Uint8Array(1))).toMatchObject({ success: false, status: 429 })
+
+    // Each connection has its own bucket
+    expect(rateLimiter.checkMessage(fakeConnection('conn-2', '10.0.0.1'), new Uint8Array(1))).toBeNull()
+    expect(rateLimiter.metrics).toMatchObject({ oversized: 1, rateLimited: 1 })
+  })
+
+  test('should move a re-registered address to its new IP', () => {
+    const rateLimiter = createRateLimiter({ maxRegistrationsPerIp: 2 })
+    const home = fakeConnection('conn-1', '10.0.0.1')
+    const mobile = fakeConnection('conn-2', '10.0.0.2')
+
+    rateLimiter.trackRegistration(home, TEST_CONFIG.testSS58AddressA)
+    rateLimiter.trackRegistration(home, TEST_CONFIG.testSS58AddressB)
+    expect(() => rateLimiter.trackRegistration(home, TEST_CONFIG.testSS58AddressAlice)).toThrow('Too many registered addresses')
+
+    // Re-registering the same address from the same IP does not count twice
+    rateLimiter.trackRegistration(home, TEST_CONFIG.testSS58AddressB)
+    rateLimiter.trackRegistration(mobile, TEST_CONFIG.testSS58AddressB)
+    rateLimiter.trackRegistration(home, TEST_CONFIG.testSS58AddressAlice)
+
+    rateLimiter.releaseRegistration(TEST_CONFIG.testSS58AddressA)
+    rateLimiter.trackRegistration(home, 'another-address')
+    expect(rateLimiter.metrics.registrationsRejected).toBe(1)
+  })
+})
+
+// Test Suite: Relay Audit Log
+test.describe('relay audit log:', () => {
+  const readAuditFile = (filePath) => readFileSync(filePath, 'utf8').trim().split('\n').map(line => JSON.parse(line))
+
+  test('should continue the hash chain across restarts and detect tampering', () => {
+    const filePath = join(mkdtempSync(join(tmpdir(), 'audit-log-')), 'audit.jsonl')
+
+    const firstRun = startAuditLog({ filePath })
+    relayEvents.emit('address:registered', { ss58Address: TEST_CONFIG.testSS58AddressA })
+    relayEvents.emit('registration:failed', { ss58Address: 'x'.repeat(1000), reason: 'Invalid signature' })
+    firstRun.stop()
+
+    const secondRun = startAuditLog({ filePath })
+    relayEvents.emit('address:registered', { ss58Address: TEST_CONFIG.testSS58AddressB })
+    secondRun.stop()
+
+    const entries = readAuditFile(filePath)
+    expect(entries.map(entry => entry.index)).toEqual([0, 1, 2])
+    expect(entries[1].data.ss58Address.length).toBeLessThan(200)
+    expect(verifyAuditLog(entries)).toMatchObject({ valid: true, head: entries[2].hash, length: 3 })
+
+    const { startIndex, previousHash, entries: exported } = secondRun.export()
+    expect(verifyAuditLog(exported, { startIndex, previousHash })).toMatchObject({ valid: true, head: entries[2].hash })
+
+    entries[1] = { ...entries[1], data: { ss58Address: TEST_CONFIG.testSS58AddressB } }
+    expect(verifyAuditLog(entries)).toEqual({ valid: false, brokenAt: 1 })
+    expect(verifyAuditLog(entries.slice(0, 1).concat(entries.slice(2)))).toEqual({ valid: false, brokenAt: 1 })
+  })
+
+  test('should refuse to continue a file whose last entry is corrupt', () => {
+    const filePath = join(mkdtempSync(join(tmpdir(), 'audit-log-')), 'audit.jsonl')
+
+    const firstRun = startAuditLog({ filePath })
+    relayEvents.emit('address:registered', { ss58Address: TEST_CONFIG.testSS58AddressA })
+    firstRun.stop()
+
+    // A write cut off by a crash
+    appendFileSync(filePath, '{"index":1,"timestamp":')
+    const contents = readFileSync(filePath, 'utf8')
+
+    expect(() => startAuditLog({ filePath })).toThrow(`Audit log ${filePath} could not be read`)
+    expect(readFileSync(filePath, 'utf8')).toBe(contents)
+  })
+
+  test('should keep only the newest entries in memory', () => {
+    const auditLog = startAuditLog({ maxEntries: 2 })
+    for (let i = 0; i < 5; i++) {
+      relayEvents.emit('registration:failed', { ss58Address: `address-${i}`, reason: 'Invalid signature' })
+    }
+    auditLog.stop()
+
+    const { startIndex, previousHash, entries } = auditLog.export()
+    expect(entries.map(entry => entry.index)).toEqual([3, 4])
+    expect(startIndex).toBe(3)
+    expect(auditLog.verify()).toMatchObject({ valid: true, length: 5 })
+    expect(verifyAuditLog(entries, { startIndex, previousHash }).valid).toBe(true)
+  })
+})
+
+// Test Suite: Relay Webhooks
+test.describe('relay webhooks:', () => {
+  test('should deliver events signed with the webhook secret', async () => {
+    const receiver = await startRecordingServer()
+    const secret = 'webhook-test-secret'
+    const webhooks = startWebhookNotifier({ urls: [receiver.url], secret })
+
+    const delivered = receiver.nextRequest()
+    relayEvents.emit('permission:requested', { requestId: 'request-1', requesterSS58Address: TEST_CONFIG.testSS58AddressA })
+    const { headers, body } = await delivered
+    webhooks.stop()
+    await receiver.close()
+
+    expect(headers['content-type']).toBe('application/json')
+    expect(headers['x-relay-signature']).toBe(`sha256=${createHmac('sha256', secret).update(body).digest('hex')}`)
+    expect(JSON.parse(body)).toMatchObject({
+      event: 'permission:requested',
+      data: { requestId: 'request-1', requesterSS58Address: TEST_CONFIG.testSS58AddressA }
+    })
+  })
+
+  test('should refuse to start without a secret', () => {
+    expect(() => startWebhookNotifier({ urls: ['http://127.0.0.1:1/'] })).toThrow('Webhooks require a secret')
+  })
+})
+
+// Test Suite: Relay Push Notifications
+test.describe('relay push notifications:', () => {
+  const permissionRequest = {
+    requestId: 'request-push',
+    requesterSS58Address: TEST_CONFIG.testSS58AddressA,
+    targetSS58Address: TEST_CONFIG.testSS58AddressB
+  }
+  const expectedDigest = createHash('sha256')
+    .update(JSON.stringify([permissionRequest.requestId, permissionRequest.requesterSS58Address, permissionRequest.targetSS58Address]))
+    .digest('hex')
+
+  test('should send the target a notification with the request digest', async () => {
+    const gateway = await startRecordingServer()
+    const notifier = startPushNotifier({ send: createPushGatewaySender({ url: gateway.url, token: 'gateway-token' }) })
+
+    const delivered = gateway.nextRequest()
+    relayEvents.emit('permission:requested', permissionRequest)
+    const { headers, body } = await delivered
+    notifier.stop()
+    await gateway.close()
+
+    expect(headers.authorization).toBe('Bearer gateway-token')
+    expect(JSON.parse(body)).toEqual({
+      recipient: TEST_CONFIG.testSS58AddressB,
+      requestId: 'request-push',
+      requesterSS58Address: TEST_CONFIG.testSS58AddressA,
+      digest: expectedDigest
+    })
+  })
+
+  test('should log a failed delivery without throwing', async () => {
+    const gateway = await startRecordingServer(503)
+    const send = createPushGatewaySender({ url: gateway.url })
+    await expect(send({ recipient: TEST_CONFIG.testSS58AddressB })).rejects.toThrow('HTTP 503')
+    await gateway.close()
+
+    const logged = []
+    const log = console.log
+    console.log = (message) => logged.push(message)
+    let sent
+    const notifier = startPushNotifier({
+      send: async (notification) => {
+        sent = notification
+        throw new Error('gateway unreachable')
+      }
+    })
+    try {
+      relayEvents.emit('permission:requested', permissionRequest)
+      await expect.poll(() => logged).toContain('ERROR: Push notification for request-push failed: gateway unreachable')
+    } finally {
+      notifier.stop()
+      console.log = log
+    }
+    expect(sent.digest).toBe(expectedDigest)
+  })
 })
 
 // Test Helper Functions